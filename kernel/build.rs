@@ -4,4 +4,21 @@ fn main() {
     println!("cargo:rustc-link-arg=-Tlinker-{arch}.ld");
     // ..and to re-run if it changes.
     println!("cargo:rerun-if-changed=linker-{arch}.ld");
+
+    // heap debugging finds the callers of allocations by following frame pointers
+    println!("cargo::rustc-check-cfg=cfg(frame_pointers)");
+    let rustflags = std::env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+    let frame_pointers = rustflags.split('\x1f').any(|flag| {
+        matches!(
+            flag.trim_start_matches("-C"),
+            "force-frame-pointers"
+                | "force-frame-pointers=yes"
+                | "force-frame-pointers=y"
+                | "force-frame-pointers=on"
+                | "force-frame-pointers=true"
+        )
+    });
+    if frame_pointers {
+        println!("cargo::rustc-cfg=frame_pointers");
+    }
 }
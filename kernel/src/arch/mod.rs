@@ -13,6 +13,7 @@ static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
 pub fn init() {
     gdt::init();
     idt::init();
+    pat::init();
 
     let rsdp_addr = RSDP_REQUEST
         .get_response()
@@ -21,13 +22,22 @@ pub fn init() {
 
     unsafe {
         acpi::init(rsdp_addr);
+        numa::init(&acpi::ACPI_PLATFORM.get_unchecked().tables);
+        hpet::init(&acpi::ACPI_PLATFORM.get_unchecked().tables);
 
         if let InterruptModel::Apic(apic) = &acpi::ACPI_PLATFORM.get_unchecked().interrupt_model {
             apic::init(apic)
         } else {
             panic!("no xapic interrupt controller found")
         };
+
+        rtc::init(&acpi::ACPI_PLATFORM.get_unchecked().tables);
     }
 
+    apic::start_timer(
+        apic::TimerMode::Periodic,
+        crate::time::tick::DEFAULT_FREQUENCY,
+    );
+
     ::x86_64::instructions::interrupts::enable();
 }
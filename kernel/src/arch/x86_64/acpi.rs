@@ -4,13 +4,15 @@ use acpi::{
     AcpiTables, Handle, Handler, PciAddress, PhysicalMapping, aml::AmlError, platform::AcpiPlatform,
 };
 use spin::Once;
-use x86_64::{
-    PhysAddr,
-    structures::paging::{PageTableFlags, Size4KiB},
-};
+use x86_64::{PhysAddr, VirtAddr};
 
-use crate::{map_page, mem};
+use crate::{
+    mem::{mmio, vmm::CacheMode},
+    time,
+};
 
+/// The tables referenced by the platform are only valid until
+/// [`crate::mem::reclaim::reclaim_acpi_memory`] is called
 pub static ACPI_PLATFORM: Once<AcpiPlatform<AcpiHandler>> = Once::new();
 
 /// # Safety
@@ -34,20 +36,13 @@ impl Handler for AcpiHandler {
         physical_address: usize,
         size: usize,
     ) -> PhysicalMapping<Self, T> {
-        // should already be mapped in page tables so another mapping is not needed
-
-        let phys_addr = PhysAddr::new(physical_address as u64);
-        let virt_addr = mem::phys_to_virt(phys_addr);
-
-        map_page!(
-            phys_addr,
-            virt_addr,
-            Size4KiB,
-            PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::NO_CACHE
-                | PageTableFlags::WRITE_THROUGH
-        );
+        // the tables live in ram, mapping them with another memory type than the hhdm would alias
+        let virt_addr = mmio::ioremap(
+            PhysAddr::new(physical_address as u64),
+            size as u64,
+            CacheMode::WriteBack,
+        )
+        .expect("failed to map acpi region");
 
         PhysicalMapping {
             physical_start: physical_address,
@@ -59,7 +54,10 @@ impl Handler for AcpiHandler {
         }
     }
 
-    fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {}
+    fn unmap_physical_region<T>(region: &PhysicalMapping<Self, T>) {
+        mmio::iounmap(VirtAddr::from_ptr(region.virtual_start.as_ptr()))
+            .expect("failed to unmap acpi region");
+    }
 
     fn read_u8(&self, _address: usize) -> u8 {
         unimplemented!()
@@ -142,15 +140,16 @@ impl Handler for AcpiHandler {
     }
 
     fn nanos_since_boot(&self) -> u64 {
-        unimplemented!()
+        time::monotonic_now().as_nanos()
     }
 
-    fn stall(&self, _microseconds: u64) {
-        unimplemented!()
+    fn stall(&self, microseconds: u64) {
+        time::udelay(microseconds);
     }
 
-    fn sleep(&self, _milliseconds: u64) {
-        unimplemented!()
+    fn sleep(&self, milliseconds: u64) {
+        // aml runs synchronously, there is no task to put to sleep
+        time::udelay(milliseconds.saturating_mul(1000));
     }
 
     fn create_mutex(&self) -> Handle {
@@ -1,18 +1,82 @@
-use acpi::platform::interrupt::Apic;
-use spin::Once;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use acpi::platform::interrupt::{Apic, Polarity, TriggerMode};
+use spin::{Mutex, Once};
 use x2apic::{
     ioapic::{IoApic, IrqFlags, IrqMode, RedirectionTableEntry},
     lapic::{LocalApic, LocalApicBuilder},
 };
 use x86_64::{
     PhysAddr, VirtAddr,
-    instructions::port::Port,
-    structures::paging::{PageTableFlags, Size4KiB},
+    instructions::{interrupts, port::Port},
+    structures::paging::{PageSize, Size4KiB},
 };
 
-use crate::{arch::idt::InterruptIndex, map_page, mem::phys_to_virt, println};
+use super::CALIBRATION_MICROS;
+use crate::{
+    arch::{hpet, idt::InterruptIndex, pit},
+    mem::{mmio, vmm::CacheMode},
+    println,
+    time::tick,
+};
 
 static LAPIC_BASE_ADDR: Once<u64> = Once::new();
+static IOAPIC: Once<Mutex<RoutedIoApic>> = Once::new();
+/// Global system interrupt and flags of every legacy isa irq
+static ISA_IRQS: Once<[(u32, IrqFlags); ISA_IRQ_COUNT]> = Once::new();
+/// Timer ticks per second with the divider set to [`TIMER_DIVIDE_BY_16`]
+static TIMER_FREQUENCY: Once<u64> = Once::new();
+/// Initial count the interrupt handler rearms the timer with in one-shot mode, 0 in periodic mode
+static ONE_SHOT_COUNT: AtomicU32 = AtomicU32::new(0);
+/// Initial count of every tick, 0 while the timer isn't running
+static TICK_COUNT: AtomicU32 = AtomicU32::new(0);
+
+// local apic registers
+const LVT_TIMER: u64 = 0x320;
+const TIMER_INITIAL_COUNT: u64 = 0x380;
+const TIMER_CURRENT_COUNT: u64 = 0x390;
+const TIMER_DIVIDE_CONFIG: u64 = 0x3e0;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+const ISA_IRQ_COUNT: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteError {
+    /// The io apic has no input for the global system interrupt
+    UnknownGsi(u32),
+}
+
+/// The io apic external interrupts are routed through
+struct RoutedIoApic {
+    ioapic: IoApic,
+    /// First global system interrupt of the io apic
+    gsi_base: u32,
+    entries: u32,
+    /// Apic id of the cpu the interrupts are delivered to
+    dest: u8,
+}
+
+// the registers are only accessed with the lock held
+unsafe impl Send for RoutedIoApic {}
+
+impl RoutedIoApic {
+    fn input(&self, gsi: u32) -> Option<u8> {
+        gsi.checked_sub(self.gsi_base)
+            .filter(|&input| input < self.entries)
+            .map(|input| input as u8)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// The timer reloads itself
+    Periodic,
+    /// The timer is rearmed by every interrupt
+    OneShot,
+}
 
 /// # Safety
 ///
@@ -20,56 +84,124 @@ static LAPIC_BASE_ADDR: Once<u64> = Once::new();
 pub unsafe fn init(apic: &Apic) {
     disable_8259_pics();
 
-    let lapic_phys_addr = PhysAddr::new(apic.local_apic_address);
-    let lapic_virt_addr = phys_to_virt(lapic_phys_addr);
+    let lapic_virt_addr = mmio::ioremap(
+        PhysAddr::new(apic.local_apic_address),
+        Size4KiB::SIZE,
+        CacheMode::Uncached,
+    )
+    .expect("failed to map the local apic");
 
     LAPIC_BASE_ADDR.call_once(|| lapic_virt_addr.as_u64());
 
-    map_page!(
-        lapic_phys_addr,
-        lapic_virt_addr,
-        Size4KiB,
-        PageTableFlags::PRESENT
-            | PageTableFlags::WRITABLE
-            | PageTableFlags::NO_CACHE
-            | PageTableFlags::WRITE_THROUGH
-    );
-
     let lapic = init_lapic(lapic_virt_addr);
+    TIMER_FREQUENCY.call_once(calibrate_timer);
+
+    ISA_IRQS.call_once(|| isa_irqs(apic));
 
-    let first_ioapic_phys_addr =
-        PhysAddr::new(apic.io_apics.first().expect("no ioapic found").address as u64);
-    let first_ioapic_virt_addr = phys_to_virt(first_ioapic_phys_addr);
+    let first_ioapic = apic.io_apics.first().expect("no ioapic found");
+    let first_ioapic_phys_addr = PhysAddr::new(first_ioapic.address as u64);
+    let first_ioapic_virt_addr =
+        mmio::ioremap(first_ioapic_phys_addr, Size4KiB::SIZE, CacheMode::Uncached)
+            .expect("failed to map the io apic");
 
-    map_page!(
-        first_ioapic_phys_addr,
+    init_ioapic(
         first_ioapic_virt_addr,
-        Size4KiB,
-        PageTableFlags::PRESENT
-            | PageTableFlags::WRITABLE
-            | PageTableFlags::NO_CACHE
-            | PageTableFlags::WRITE_THROUGH
+        first_ioapic.global_system_interrupt_base,
+        &lapic,
     );
 
-    init_ioapic(first_ioapic_virt_addr, &lapic);
+    route_isa_irq(1, InterruptIndex::Keyboard).expect("failed to route the keyboard interrupt");
 }
 
-fn init_ioapic(ioapic_base_addr: VirtAddr, lapic: &LocalApic) {
+fn init_ioapic(ioapic_base_addr: VirtAddr, gsi_base: u32, lapic: &LocalApic) {
     let mut ioapic = unsafe { IoApic::new(ioapic_base_addr.as_u64()) };
+    let entries = unsafe { ioapic.max_table_entry() } as u32 + 1;
+    let dest = unsafe { lapic.id() } as u8;
 
-    // enables all entries
-    // for i in 32..(255 - 32) {
-    let mut entry = RedirectionTableEntry::default();
-    entry.set_mode(IrqMode::Fixed);
-    entry.set_flags(IrqFlags::LEVEL_TRIGGERED | IrqFlags::LOW_ACTIVE);
-    entry.set_dest(unsafe { lapic.id() } as u8);
-    entry.set_vector(0x21);
+    IOAPIC.call_once(|| {
+        Mutex::new(RoutedIoApic {
+            ioapic,
+            gsi_base,
+            entries,
+            dest,
+        })
+    });
+}
 
-    unsafe {
-        ioapic.set_table_entry(0x1, entry);
-        ioapic.enable_irq(0x1);
+/// Isa irqs are identity mapped and edge triggered active high, unless the madt overrides them
+fn isa_irqs(apic: &Apic) -> [(u32, IrqFlags); ISA_IRQ_COUNT] {
+    let mut irqs = core::array::from_fn(|irq| (irq as u32, IrqFlags::empty()));
+
+    for source_override in &apic.interrupt_source_overrides {
+        let Some(irq) = irqs.get_mut(source_override.isa_source as usize) else {
+            continue;
+        };
+
+        let mut flags = IrqFlags::empty();
+        if matches!(source_override.polarity, Polarity::ActiveLow) {
+            flags |= IrqFlags::LOW_ACTIVE;
+        }
+        if matches!(source_override.trigger_mode, TriggerMode::Level) {
+            flags |= IrqFlags::LEVEL_TRIGGERED;
+        }
+
+        *irq = (source_override.global_system_interrupt, flags);
     }
-    // }
+
+    irqs
+}
+
+/// Delivers the legacy isa irq `irq` to `vector` of the boot cpu, following the overrides of the
+/// firmware
+pub fn route_isa_irq(irq: u8, vector: InterruptIndex) -> Result<(), RouteError> {
+    let isa_irqs = ISA_IRQS.get().expect("io apic not initialized");
+    let (gsi, flags) = *isa_irqs
+        .get(irq as usize)
+        .unwrap_or_else(|| panic!("isa irq {irq} doesn't exist"));
+
+    route_irq(gsi, vector, flags)
+}
+
+/// Whether the io apic has an input for the global system interrupt `gsi`
+pub fn has_gsi(gsi: u32) -> bool {
+    let ioapic = IOAPIC.get().expect("io apic not initialized");
+    interrupts::without_interrupts(|| ioapic.lock().input(gsi).is_some())
+}
+
+/// Delivers the global system interrupt `gsi` to `vector` of the boot cpu and unmasks it
+pub fn route_irq(gsi: u32, vector: InterruptIndex, flags: IrqFlags) -> Result<(), RouteError> {
+    let ioapic = IOAPIC.get().expect("io apic not initialized");
+
+    interrupts::without_interrupts(|| {
+        let mut ioapic = ioapic.lock();
+        let input = ioapic.input(gsi).ok_or(RouteError::UnknownGsi(gsi))?;
+
+        let mut entry = RedirectionTableEntry::default();
+        entry.set_mode(IrqMode::Fixed);
+        entry.set_flags(flags);
+        entry.set_dest(ioapic.dest);
+        entry.set_vector(vector.as_u8());
+
+        unsafe {
+            ioapic.ioapic.set_table_entry(input, entry);
+            ioapic.ioapic.enable_irq(input);
+        }
+
+        Ok(())
+    })
+}
+
+/// Masks the global system interrupt `gsi`
+pub fn mask_irq(gsi: u32) -> Result<(), RouteError> {
+    let ioapic = IOAPIC.get().expect("io apic not initialized");
+
+    interrupts::without_interrupts(|| {
+        let mut ioapic = ioapic.lock();
+        let input = ioapic.input(gsi).ok_or(RouteError::UnknownGsi(gsi))?;
+        unsafe { ioapic.ioapic.disable_irq(input) };
+
+        Ok(())
+    })
 }
 
 fn init_lapic(lapic_base_addr: VirtAddr) -> LocalApic {
@@ -88,6 +220,91 @@ fn init_lapic(lapic_base_addr: VirtAddr) -> LocalApic {
     lapic
 }
 
+/// Makes the local apic timer interrupt `frequency` times per second, replacing the current
+/// setup. The interrupts drive [`tick`].
+pub fn start_timer(mode: TimerMode, frequency: u32) {
+    let timer_frequency = *TIMER_FREQUENCY.get().expect("local apic not initialized");
+    let count = u32::try_from(timer_frequency / frequency as u64)
+        .ok()
+        .filter(|&count| count > 0)
+        .unwrap_or_else(|| panic!("timer can't run at {frequency} Hz"));
+
+    let vector = InterruptIndex::Timer.as_usize() as u32;
+    let lvt = match mode {
+        TimerMode::Periodic => vector | LVT_TIMER_PERIODIC,
+        TimerMode::OneShot => vector,
+    };
+
+    ONE_SHOT_COUNT.store(
+        if mode == TimerMode::OneShot { count } else { 0 },
+        Ordering::Relaxed,
+    );
+    TICK_COUNT.store(count, Ordering::Relaxed);
+    tick::set_frequency(frequency);
+
+    unsafe {
+        write_lapic(TIMER_DIVIDE_CONFIG, TIMER_DIVIDE_BY_16);
+        write_lapic(LVT_TIMER, lvt);
+        write_lapic(TIMER_INITIAL_COUNT, count);
+    }
+}
+
+/// Timer ticks per second, once the local apic has been initialized
+pub fn timer_frequency() -> Option<u64> {
+    TIMER_FREQUENCY.get().copied()
+}
+
+/// Timer ticks counted by the system tick so far, `None` while the timer isn't running.
+///
+/// The value jumps back by one tick when the timer reloaded but the interrupt hasn't been
+/// handled yet, and restarting the timer at another frequency scales the past ticks.
+pub fn timer_count() -> Option<u64> {
+    interrupts::without_interrupts(|| {
+        let count = TICK_COUNT.load(Ordering::Relaxed);
+        if count == 0 {
+            return None;
+        }
+
+        let remaining = unsafe { read_lapic(TIMER_CURRENT_COUNT) };
+        Some(tick::ticks() * count as u64 + (count - remaining.min(count)) as u64)
+    })
+}
+
+/// Counts the timer ticks per second, using the hpet as reference or the pit without one
+fn calibrate_timer() -> u64 {
+    let vector = InterruptIndex::Timer.as_usize() as u32;
+
+    let remaining = unsafe {
+        write_lapic(TIMER_DIVIDE_CONFIG, TIMER_DIVIDE_BY_16);
+        write_lapic(LVT_TIMER, vector | LVT_MASKED);
+        write_lapic(TIMER_INITIAL_COUNT, u32::MAX);
+
+        if hpet::wait(CALIBRATION_MICROS).is_err() {
+            pit::wait(CALIBRATION_MICROS);
+        }
+
+        let remaining = read_lapic(TIMER_CURRENT_COUNT);
+        write_lapic(TIMER_INITIAL_COUNT, 0);
+        remaining
+    };
+
+    (u32::MAX - remaining) as u64 * 1_000_000 / CALIBRATION_MICROS
+}
+
+/// Handles a timer interrupt, rearming the timer in one-shot mode
+///
+/// # Safety
+///
+/// Must only be called from the timer interrupt handler
+pub unsafe fn handle_timer_interrupt() {
+    let count = ONE_SHOT_COUNT.load(Ordering::Relaxed);
+    if count != 0 {
+        unsafe { write_lapic(TIMER_INITIAL_COUNT, count) };
+    }
+
+    tick::handle_tick();
+}
+
 fn disable_8259_pics() {
     let mut master_pic = Port::new(0x21);
     let mut slave_pic = Port::new(0xa1);
@@ -98,6 +315,26 @@ fn disable_8259_pics() {
     }
 }
 
+/// # Safety
+///
+/// The local apic must have been mapped and `reg` must be a readable register
+unsafe fn read_lapic(reg: u64) -> u32 {
+    unsafe {
+        let ptr = (LAPIC_BASE_ADDR.get_unchecked() + reg) as *const u32;
+        core::ptr::read_volatile(ptr)
+    }
+}
+
+/// # Safety
+///
+/// The local apic must have been mapped and `reg` must be a writable register
+unsafe fn write_lapic(reg: u64, value: u32) {
+    unsafe {
+        let ptr = (LAPIC_BASE_ADDR.get_unchecked() + reg) as *mut u32;
+        core::ptr::write_volatile(ptr, value);
+    }
+}
+
 /// # Safety
 ///
 /// This function must only be called after the LAPIC controller has been initialized
@@ -0,0 +1,120 @@
+//! The counters of the platform as clock sources.
+//!
+//! An invariant tsc is the best source, it's the cheapest to read and the most precise. The hpet
+//! comes next, then the lapic timer, which is only as steady as the system tick, and the pit as
+//! the last resort.
+
+use alloc::{vec, vec::Vec};
+
+use super::{apic, hpet, pit, tsc};
+use crate::time::{
+    clocksource::ClockSource,
+    tick::{self, TickCallback},
+};
+
+struct Tsc;
+struct Hpet;
+struct LapicTimer;
+struct Pit;
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        // without invariance the rate changes with the cpu frequency
+        if tsc::is_invariant() { 400 } else { 10 }
+    }
+
+    fn frequency(&self) -> u64 {
+        tsc::frequency()
+    }
+
+    fn read(&self) -> u64 {
+        tsc::read()
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        300
+    }
+
+    fn frequency(&self) -> u64 {
+        hpet::frequency().expect("hpet not initialized")
+    }
+
+    fn read(&self) -> u64 {
+        hpet::count().expect("hpet not initialized")
+    }
+}
+
+impl ClockSource for LapicTimer {
+    fn name(&self) -> &'static str {
+        "lapic"
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn frequency(&self) -> u64 {
+        apic::timer_frequency().expect("local apic not initialized")
+    }
+
+    fn read(&self) -> u64 {
+        apic::timer_count().expect("lapic timer not running")
+    }
+}
+
+impl ClockSource for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn rating(&self) -> u32 {
+        50
+    }
+
+    fn frequency(&self) -> u64 {
+        pit::FREQUENCY
+    }
+
+    fn read(&self) -> u64 {
+        pit::count().expect("pit counter not running")
+    }
+
+    fn enable(&self) {
+        pit::start_counter();
+        watch_wraps("pit", |_| {
+            pit::count();
+        });
+    }
+}
+
+/// Reads a counter narrower than 64 bits with `read` on every tick. Such counters are extended in
+/// software, which only works if they are read at least once per wrap, and the tick comes far more
+/// often than that.
+pub(super) fn watch_wraps(name: &str, read: TickCallback) {
+    tick::register_callback(read)
+        .unwrap_or_else(|err| panic!("failed to register the {name} tick callback: {err:?}"));
+}
+
+/// The clock sources the machine has
+pub fn clock_sources() -> Vec<&'static dyn ClockSource> {
+    let mut sources: Vec<&'static dyn ClockSource> = vec![&Tsc, &Pit];
+
+    if hpet::is_present() {
+        sources.push(&Hpet);
+    }
+    if apic::timer_count().is_some() {
+        sources.push(&LapicTimer);
+    }
+
+    sources
+}
@@ -1,4 +1,4 @@
-use core::arch::asm;
+use alloc::boxed::Box;
 
 use spin::Once;
 use x86_64::{
@@ -14,30 +14,21 @@ use x86_64::{
     },
 };
 
+use crate::mem::stack::{KERNEL_STACK_SIZE, KernelStack};
+
+/// Double faults get their own stack, a page fault on an overflowed kernel stack can't push its
+/// frame and escalates to one
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+const INTERRUPT_STACK_SIZE: u64 = 4096 * 5;
+
 pub static TSS: Once<TaskStateSegment> = Once::new();
 pub static GDT: Once<GlobalDescriptorTable> = Once::new();
 
 fn init_tss() {
     let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096 * 5;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-        let stack_start = VirtAddr::from_ptr(&raw const STACK);
-        stack_start + STACK_SIZE as u64 // stack end
-    };
-
-    let rsp: u64;
-    unsafe {
-        asm!(
-        "mov {}, rsp",
-        out(reg) rsp
-        );
-    }
-
-    tss.privilege_stack_table[0] = VirtAddr::new(rsp);
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_top(INTERRUPT_STACK_SIZE);
+    tss.privilege_stack_table[0] = stack_top(KERNEL_STACK_SIZE);
 
     TSS.call_once(|| tss);
 }
@@ -67,3 +58,9 @@ pub fn init() {
         load_tss(tss_selector);
     }
 }
+
+/// Allocates a stack that is used for the rest of the kernel's lifetime
+fn stack_top(size: u64) -> VirtAddr {
+    let stack = KernelStack::new(size).expect("failed to allocate a kernel stack");
+    Box::leak(Box::new(stack)).top()
+}
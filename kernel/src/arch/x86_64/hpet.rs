@@ -0,0 +1,391 @@
+//! The high precision event timer.
+//!
+//! The HPET is found through its acpi table. Its main counter runs at a fixed frequency from the
+//! moment [`init`] enables it, which makes it the reference for [`nanos`] and for calibrating the
+//! other timers. Each comparator can raise an interrupt when the counter reaches a value, once or
+//! periodically, routed through the io apic to [`InterruptIndex::Hpet`].
+
+use core::{
+    arch::x86_64::_rdtsc,
+    hint,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use acpi::{AcpiTables, sdt::hpet::HpetInfo};
+use spin::{Mutex, Once};
+use x2apic::ioapic::IrqFlags;
+use x86_64::{PhysAddr, VirtAddr, instructions::interrupts};
+
+use super::{acpi::AcpiHandler, apic, clocksource, idt::InterruptIndex};
+use crate::{
+    mem::{mmio, vmm::CacheMode},
+    println,
+};
+
+// general registers
+const CAPABILITIES: u64 = 0x000;
+const CONFIG: u64 = 0x010;
+const INTERRUPT_STATUS: u64 = 0x020;
+const MAIN_COUNTER: u64 = 0x0f0;
+
+/// The general registers followed by the registers of as many comparators as a block can have,
+/// the real number is only known once the capabilities have been read
+const REGISTERS_SIZE: u64 = comparator_config(MAX_COMPARATORS as u8);
+
+// comparator registers, relative to the comparator's block
+const fn comparator_config(index: u8) -> u64 {
+    0x100 + 0x20 * index as u64
+}
+
+const fn comparator_value(index: u8) -> u64 {
+    0x108 + 0x20 * index as u64
+}
+
+// capabilities
+const COUNTER_64_BIT: u64 = 1 << 13;
+
+// general config
+const ENABLE: u64 = 1 << 0;
+const LEGACY_ROUTING: u64 = 1 << 1;
+
+// comparator config
+const LEVEL_TRIGGERED: u64 = 1 << 1;
+const INTERRUPT_ENABLE: u64 = 1 << 2;
+const PERIODIC: u64 = 1 << 3;
+const PERIODIC_CAPABLE: u64 = 1 << 4;
+const COMPARATOR_64_BIT: u64 = 1 << 5;
+const SET_ACCUMULATOR: u64 = 1 << 6;
+const FORCE_32_BIT: u64 = 1 << 8;
+const ROUTE_SHIFT: u64 = 9;
+const ROUTE_MASK: u64 = 0x1f << ROUTE_SHIFT;
+const FSB_ENABLE: u64 = 1 << 14;
+
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+const FEMTOS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// The spec allows at most 32 comparators per block
+const MAX_COMPARATORS: usize = 32;
+
+/// How long the tsc is measured against the hpet
+const TSC_CALIBRATION_MICROS: u64 = 10_000;
+
+static HPET: Once<Hpet> = Once::new();
+static HANDLERS: Mutex<[Option<ComparatorHandler>; MAX_COMPARATORS]> =
+    Mutex::new([None; MAX_COMPARATORS]);
+/// Last value read from a 32 bit main counter, extended to 64 bits
+static LAST_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Called from the interrupt handler when a comparator fires
+pub type ComparatorHandler = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// The machine has no hpet or it hasn't been initialized
+    NotPresent,
+    /// The comparator doesn't exist
+    NoSuchComparator(u8),
+    /// The comparator can't fire periodically
+    NotPeriodic(u8),
+    /// None of the io apic inputs the comparator can use exists
+    NoRoute(u8),
+    /// The deadline doesn't fit in the comparator
+    OutOfRange,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComparatorMode {
+    /// Fires once after the delay
+    OneShot,
+    /// Fires every time the delay passes
+    Periodic,
+}
+
+struct Hpet {
+    base: VirtAddr,
+    /// Length of a counter tick in femtoseconds
+    period: u64,
+    comparators: u8,
+    counter_64_bit: bool,
+}
+
+impl Hpet {
+    /// # Safety
+    ///
+    /// `reg` must be a register of the block
+    unsafe fn read(&self, reg: u64) -> u64 {
+        unsafe { core::ptr::read_volatile((self.base + reg).as_ptr()) }
+    }
+
+    /// # Safety
+    ///
+    /// `reg` must be a register of the block and writing `value` must not break other users
+    unsafe fn write(&self, reg: u64, value: u64) {
+        unsafe { core::ptr::write_volatile((self.base + reg).as_mut_ptr(), value) }
+    }
+
+    fn frequency(&self) -> u64 {
+        FEMTOS_PER_SECOND / self.period
+    }
+
+    fn count(&self) -> u64 {
+        let raw = unsafe { self.read(MAIN_COUNTER) };
+        if self.counter_64_bit {
+            return raw;
+        }
+
+        // extends the 32 bit counter, which works as long as it is read at least once per wrap
+        let mut last = LAST_COUNT.load(Ordering::Relaxed);
+        loop {
+            let mut count = (last & !0xffff_ffff) | (raw & 0xffff_ffff);
+            if count < last {
+                // a read racing with a newer one isn't a wrap
+                if last - count < 1 << 31 {
+                    return last;
+                }
+                count += 1 << 32;
+            }
+
+            match LAST_COUNT.compare_exchange_weak(
+                last,
+                count,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return count,
+                Err(newer) => last = newer,
+            }
+        }
+    }
+
+    fn ticks_for(&self, nanos: u64) -> u64 {
+        (nanos as u128 * FEMTOS_PER_NANO as u128 / self.period as u128) as u64
+    }
+
+    /// Picks the io apic input for a comparator, preferring ones above the legacy irqs
+    fn route(&self, index: u8) -> Option<u32> {
+        let capable = unsafe { self.read(comparator_config(index)) } >> 32;
+        let usable = |gsi: &u32| capable & (1 << gsi) != 0 && apic::has_gsi(*gsi);
+
+        (16..32).find(usable).or_else(|| (0..16).rev().find(usable))
+    }
+}
+
+/// Enables the hpet described by the acpi tables, if there is one
+///
+/// # Safety
+///
+/// Must only be called once, with the tables of the running machine
+pub unsafe fn init(tables: &AcpiTables<AcpiHandler>) {
+    let Ok(info) = HpetInfo::new(tables) else {
+        println!("no hpet found");
+        return;
+    };
+
+    let base = mmio::ioremap(
+        PhysAddr::new(info.base_address as u64),
+        REGISTERS_SIZE,
+        CacheMode::Uncached,
+    )
+    .expect("failed to map the hpet");
+
+    let capabilities = unsafe { core::ptr::read_volatile((base + CAPABILITIES).as_ptr::<u64>()) };
+    let hpet = Hpet {
+        base,
+        period: capabilities >> 32,
+        comparators: ((capabilities >> 8) & 0x1f) as u8 + 1,
+        counter_64_bit: capabilities & COUNTER_64_BIT != 0,
+    };
+
+    if hpet.period == 0 || hpet.period > 100_000_000 {
+        crate::warning!("hpet reports an invalid period of {} fs", hpet.period);
+        mmio::iounmap(base).expect("failed to unmap the hpet");
+        return;
+    }
+
+    unsafe {
+        // stop, so that the counter and the comparators can be set up consistently
+        let config = hpet.read(CONFIG) & !(ENABLE | LEGACY_ROUTING);
+        hpet.write(CONFIG, config);
+
+        for index in 0..hpet.comparators {
+            let reg = comparator_config(index);
+            let comparator = hpet.read(reg) & !(INTERRUPT_ENABLE | PERIODIC | FSB_ENABLE);
+            hpet.write(reg, comparator);
+        }
+
+        hpet.write(MAIN_COUNTER, 0);
+        hpet.write(CONFIG, config | ENABLE);
+    }
+
+    println!(
+        "hpet: {} Hz, {} comparators, {} bit counter",
+        hpet.frequency(),
+        hpet.comparators,
+        if hpet.counter_64_bit { 64 } else { 32 }
+    );
+
+    let counter_64_bit = hpet.counter_64_bit;
+    HPET.call_once(|| hpet);
+
+    if !counter_64_bit {
+        clocksource::watch_wraps("hpet", |_| {
+            nanos();
+        });
+    }
+}
+
+pub fn is_present() -> bool {
+    HPET.get().is_some()
+}
+
+/// Counter ticks per second
+pub fn frequency() -> Option<u64> {
+    HPET.get().map(Hpet::frequency)
+}
+
+/// Raw value of the main counter, extended to 64 bits
+pub fn count() -> Option<u64> {
+    HPET.get().map(Hpet::count)
+}
+
+/// Nanoseconds since the hpet was enabled
+pub fn nanos() -> Option<u64> {
+    let hpet = HPET.get()?;
+    Some((hpet.count() as u128 * hpet.period as u128 / FEMTOS_PER_NANO as u128) as u64)
+}
+
+/// Busy waits for `micros` microseconds
+pub fn wait(micros: u64) -> Result<(), HpetError> {
+    let hpet = HPET.get().ok_or(HpetError::NotPresent)?;
+    let end = hpet.count() + hpet.ticks_for(micros * 1000);
+
+    while hpet.count() < end {
+        hint::spin_loop();
+    }
+
+    Ok(())
+}
+
+/// Measures the time stamp counter frequency in Hz
+pub fn calibrate_tsc() -> Result<u64, HpetError> {
+    let hpet = HPET.get().ok_or(HpetError::NotPresent)?;
+
+    let (tsc, count) = interrupts::without_interrupts(|| {
+        let start_count = hpet.count();
+        let start_tsc = unsafe { _rdtsc() };
+        wait(TSC_CALIBRATION_MICROS)?;
+        let end_tsc = unsafe { _rdtsc() };
+        let end_count = hpet.count();
+
+        Ok((end_tsc - start_tsc, end_count - start_count))
+    })?;
+
+    Ok((tsc as u128 * hpet.frequency() as u128 / count as u128) as u64)
+}
+
+/// Makes comparator `index` call `handler` after `nanos` nanoseconds, and every `nanos`
+/// nanoseconds after that in periodic mode. Replaces what the comparator did before.
+pub fn arm_comparator(
+    index: u8,
+    mode: ComparatorMode,
+    nanos: u64,
+    handler: ComparatorHandler,
+) -> Result<(), HpetError> {
+    let hpet = HPET.get().ok_or(HpetError::NotPresent)?;
+    if index >= hpet.comparators {
+        return Err(HpetError::NoSuchComparator(index));
+    }
+
+    let reg = comparator_config(index);
+    let config = unsafe { hpet.read(reg) };
+    if mode == ComparatorMode::Periodic && config & PERIODIC_CAPABLE == 0 {
+        return Err(HpetError::NotPeriodic(index));
+    }
+
+    let delta = hpet.ticks_for(nanos).max(1);
+    let narrow = !hpet.counter_64_bit || config & COMPARATOR_64_BIT == 0;
+    if narrow && delta > u32::MAX as u64 {
+        return Err(HpetError::OutOfRange);
+    }
+
+    let gsi = hpet.route(index).ok_or(HpetError::NoRoute(index))?;
+
+    disarm_comparator(index)?;
+    interrupts::without_interrupts(|| HANDLERS.lock()[index as usize] = Some(handler));
+
+    apic::route_irq(gsi, InterruptIndex::Hpet, IrqFlags::LEVEL_TRIGGERED)
+        .map_err(|_| HpetError::NoRoute(index))?;
+
+    let mut config = (config & !(ROUTE_MASK | PERIODIC | FSB_ENABLE | FORCE_32_BIT))
+        | LEVEL_TRIGGERED
+        | INTERRUPT_ENABLE
+        | ((gsi as u64) << ROUTE_SHIFT);
+    if narrow {
+        config |= FORCE_32_BIT;
+    }
+
+    interrupts::without_interrupts(|| unsafe {
+        let deadline = hpet.count() + delta;
+
+        match mode {
+            ComparatorMode::OneShot => {
+                hpet.write(reg, config);
+                hpet.write(comparator_value(index), deadline);
+            }
+            ComparatorMode::Periodic => {
+                // the first write sets the deadline, the second one the period
+                hpet.write(reg, config | PERIODIC | SET_ACCUMULATOR);
+                hpet.write(comparator_value(index), deadline);
+                hpet.write(comparator_value(index), delta);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Stops comparator `index` from firing
+pub fn disarm_comparator(index: u8) -> Result<(), HpetError> {
+    let hpet = HPET.get().ok_or(HpetError::NotPresent)?;
+    if index >= hpet.comparators {
+        return Err(HpetError::NoSuchComparator(index));
+    }
+
+    interrupts::without_interrupts(|| {
+        let reg = comparator_config(index);
+        unsafe {
+            hpet.write(reg, hpet.read(reg) & !(INTERRUPT_ENABLE | PERIODIC));
+            hpet.write(INTERRUPT_STATUS, 1 << index);
+        }
+
+        HANDLERS.lock()[index as usize] = None;
+    });
+
+    Ok(())
+}
+
+/// Acknowledges the comparators that fired and runs their handlers, called from the interrupt
+/// handler of [`InterruptIndex::Hpet`]
+pub(crate) fn handle_interrupt() {
+    let Some(hpet) = HPET.get() else {
+        return;
+    };
+
+    let status = unsafe { hpet.read(INTERRUPT_STATUS) };
+    if status == 0 {
+        return;
+    }
+    // the comparators are level triggered, the line stays up until the status is cleared
+    unsafe { hpet.write(INTERRUPT_STATUS, status) };
+
+    let handlers = *HANDLERS.lock();
+    for index in 0..hpet.comparators {
+        if status & (1 << index) == 0 {
+            continue;
+        }
+
+        if let Some(handler) = handlers[index as usize] {
+            handler();
+        }
+    }
+}
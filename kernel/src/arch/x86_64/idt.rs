@@ -1,12 +1,25 @@
 use spin::Once;
 use x86_64::{
     instructions::port::Port,
-    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    structures::{
+        idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+        paging::{Translate, mapper::TranslateResult},
+    },
 };
 
 use crate::{
-    arch::{gdt, x86_64::apic},
-    drivers, hlt_loop, println,
+    arch::{
+        gdt,
+        x86_64::{apic, hpet, rtc},
+    },
+    drivers,
+    mem::{
+        self,
+        fault::PageFaultError,
+        vma::{Region, RegionKind},
+    },
+    println,
+    tasks::executor,
 };
 
 pub static IDT: Once<InterruptDescriptorTable> = Once::new();
@@ -16,12 +29,14 @@ pub static IDT: Once<InterruptDescriptorTable> = Once::new();
 pub enum InterruptIndex {
     Timer = 0x20,
     Keyboard = 0x21,
+    Hpet = 0x22,
+    Rtc = 0x28,
     Error = 0x70,
     Spurious = 0xf0,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -35,8 +50,9 @@ pub fn init() {
 
     // cpu interrupts
     idt.breakpoint.set_handler_fn(breakpoint_handler);
-    idt.page_fault.set_handler_fn(page_fault_handler);
     idt.general_protection_fault.set_handler_fn(gpf_handler);
+    // page faults stay on the current stack, resolving one can fault again
+    idt.page_fault.set_handler_fn(page_fault_handler);
     unsafe {
         idt.double_fault
             .set_handler_fn(double_fault_handler)
@@ -48,6 +64,8 @@ pub fn init() {
     idt[InterruptIndex::Spurious.as_u8()].set_handler_fn(spurious_int_handler);
     idt[InterruptIndex::Error.as_u8()].set_handler_fn(error_int_handler);
     idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_int_handler);
+    idt[InterruptIndex::Hpet.as_u8()].set_handler_fn(hpet_int_handler);
+    idt[InterruptIndex::Rtc.as_u8()].set_handler_fn(rtc_int_handler);
 
     IDT.call_once(|| idt);
 
@@ -64,6 +82,18 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _err_code: u64,
 ) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    // an overflowing kernel stack faults again when the page fault pushes its frame
+    let stack = Cr2::read()
+        .ok()
+        .and_then(mem::vma::try_find_by_guard)
+        .filter(|stack| stack.kind == RegionKind::KernelStack);
+
+    if let Some(stack) = stack {
+        stack_overflow(stack, &stack_frame);
+    }
+
     panic!("EXCEPTION: DOUBLE FAULT\n{stack_frame:#?}");
 }
 
@@ -77,16 +107,41 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", err_code);
-    println!("{:#?}", stack_frame);
+    let addr = Cr2::read().expect("cr2 holds a non-canonical address");
 
-    hlt_loop();
+    let Err(err) = mem::fault::handle_page_fault(addr, err_code) else {
+        return;
+    };
+
+    if let PageFaultError::StackOverflow(stack) = err {
+        stack_overflow(stack, &stack_frame);
+    }
+
+    let mapping = match mem::MAPPER.get().and_then(|m| m.try_lock()) {
+        Some(mapper) => mapper.translate(addr),
+        None => TranslateResult::NotMapped,
+    };
+
+    panic!(
+        "EXCEPTION: PAGE FAULT\n\
+        Accessed Address: {addr:?}\n\
+        Error Code: {err_code:?}\n\
+        Reason: {err:?}\n\
+        Mapping: {mapping:?}\n\
+        {stack_frame:#?}"
+    );
+}
+
+fn stack_overflow(stack: Region, stack_frame: &InterruptStackFrame) -> ! {
+    match executor::current_task() {
+        Some(id) => panic!("kernel stack overflow in task {id}\n{stack:?}\n{stack_frame:#?}"),
+        None => panic!("kernel stack overflow outside of a task\n{stack:?}\n{stack_frame:#?}"),
+    }
 }
 
 extern "x86-interrupt" fn timer_int_handler(_stack_frame: InterruptStackFrame) {
     unsafe {
+        apic::handle_timer_interrupt();
         apic::lapic_end_of_interrupt();
     }
 }
@@ -113,3 +168,19 @@ extern "x86-interrupt" fn keyboard_int_handler(_stack_frame: InterruptStackFrame
         apic::lapic_end_of_interrupt();
     }
 }
+
+extern "x86-interrupt" fn hpet_int_handler(_stack_frame: InterruptStackFrame) {
+    hpet::handle_interrupt();
+
+    unsafe {
+        apic::lapic_end_of_interrupt();
+    }
+}
+
+extern "x86-interrupt" fn rtc_int_handler(_stack_frame: InterruptStackFrame) {
+    rtc::handle_interrupt();
+
+    unsafe {
+        apic::lapic_end_of_interrupt();
+    }
+}
@@ -1,4 +1,14 @@
 pub mod acpi;
 pub mod apic;
+pub mod clocksource;
 pub mod gdt;
+pub mod hpet;
 pub mod idt;
+pub mod numa;
+pub mod pat;
+pub mod pit;
+pub mod rtc;
+pub mod tsc;
+
+/// How long the tsc and the lapic timer are measured against the hpet or the pit
+const CALIBRATION_MICROS: u64 = 50_000;
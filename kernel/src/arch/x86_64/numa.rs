@@ -0,0 +1,185 @@
+//! NUMA topology discovery from the SRAT and SLIT.
+//!
+//! The SRAT assigns every cpu and memory range to a proximity domain, the SLIT gives the distance
+//! between every pair of domains. Neither table is parsed by the acpi crate, so their entries are
+//! read straight from the mapped table.
+
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count},
+    slice,
+};
+
+use acpi::{
+    AcpiTable, AcpiTables, PhysicalMapping,
+    sdt::{SdtHeader, Signature},
+};
+use x86_64::PhysAddr;
+
+use super::acpi::AcpiHandler;
+use crate::mem::numa::{self, NumaTopology};
+
+/// System resource affinity table
+#[repr(C, packed)]
+struct Srat {
+    header: SdtHeader,
+    _reserved: [u8; 12],
+}
+
+unsafe impl AcpiTable for Srat {
+    const SIGNATURE: Signature = Signature::SRAT;
+
+    fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+}
+
+/// System locality information table
+#[repr(C, packed)]
+struct Slit {
+    header: SdtHeader,
+    localities: u64,
+}
+
+unsafe impl AcpiTable for Slit {
+    const SIGNATURE: Signature = Signature::SLIT;
+
+    fn header(&self) -> &SdtHeader {
+        &self.header
+    }
+}
+
+// srat entry types
+const LOCAL_APIC_AFFINITY: u8 = 0;
+const MEMORY_AFFINITY: u8 = 1;
+const LOCAL_X2APIC_AFFINITY: u8 = 2;
+
+const AFFINITY_ENABLED: u32 = 1 << 0;
+const MEMORY_HOTPLUGGABLE: u32 = 1 << 1;
+
+/// Builds the NUMA topology from the acpi tables and hands it to the memory manager. Machines
+/// without an SRAT are treated as a single node.
+pub fn init(tables: &AcpiTables<AcpiHandler>) {
+    let mut topology = tables
+        .find_table::<Srat>()
+        .map(|srat| parse_srat(&srat))
+        .filter(|topology| topology.node_count() > 0)
+        .unwrap_or_else(NumaTopology::uniform);
+
+    if let Some(slit) = tables.find_table::<Slit>() {
+        parse_slit(&slit, &mut topology);
+    }
+
+    numa::init(topology, boot_apic_id());
+}
+
+fn parse_srat(srat: &PhysicalMapping<AcpiHandler, Srat>) -> NumaTopology {
+    let mut topology = NumaTopology::default();
+
+    let bytes = table_bytes(srat);
+    let mut entries = &bytes[size_of::<Srat>().min(bytes.len())..];
+
+    // every entry starts with its type and length
+    while let [kind, length, ..] = *entries {
+        let length = length as usize;
+        if length < 2 || length > entries.len() {
+            break;
+        }
+        let (entry, rest) = entries.split_at(length);
+        entries = rest;
+
+        match kind {
+            LOCAL_APIC_AFFINITY if length >= 16 => {
+                if read_u32(entry, 4) & AFFINITY_ENABLED == 0 {
+                    continue;
+                }
+                // the domain is split into the low byte and the three high bytes
+                let domain = entry[2] as u32 | (read_u32(entry, 8) & !0xff);
+                topology.add_cpu(domain, entry[3] as u32);
+            }
+            MEMORY_AFFINITY if length >= 40 => {
+                let flags = read_u32(entry, 28);
+                if flags & AFFINITY_ENABLED == 0 {
+                    continue;
+                }
+                let base = read_u64(entry, 8);
+                let length = read_u64(entry, 16);
+                if length == 0 {
+                    continue;
+                }
+                topology.add_memory(
+                    read_u32(entry, 2),
+                    PhysAddr::new(base),
+                    length,
+                    flags & MEMORY_HOTPLUGGABLE != 0,
+                );
+            }
+            LOCAL_X2APIC_AFFINITY if length >= 24 => {
+                if read_u32(entry, 12) & AFFINITY_ENABLED == 0 {
+                    continue;
+                }
+                topology.add_cpu(read_u32(entry, 4), read_u32(entry, 8));
+            }
+            _ => {}
+        }
+    }
+
+    topology
+}
+
+fn parse_slit(slit: &PhysicalMapping<AcpiHandler, Slit>, topology: &mut NumaTopology) {
+    let bytes = table_bytes(slit);
+    let localities = slit.localities;
+
+    // the matrix is indexed by proximity domain
+    let distance = |from: u32, to: u32| {
+        let (from, to) = (from as u64, to as u64);
+        if from >= localities || to >= localities {
+            return None;
+        }
+        bytes
+            .get(size_of::<Slit>() + (from * localities + to) as usize)
+            .copied()
+    };
+
+    let nodes = 0..topology.node_count();
+    let complete = nodes.clone().all(|from| {
+        nodes
+            .clone()
+            .all(|to| distance(topology.domain(from), topology.domain(to)).is_some())
+    });
+    if complete {
+        topology.set_distances(|from, to| distance(from, to).unwrap());
+    }
+}
+
+/// The whole table, including the header
+fn table_bytes<T: AcpiTable + Unpin>(table: &PhysicalMapping<AcpiHandler, T>) -> &[u8] {
+    let length = (table.header().length as usize).min(table.region_length);
+    unsafe { slice::from_raw_parts(table.virtual_start.as_ptr() as *const u8, length) }
+}
+
+fn read_u32(entry: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(entry[offset..offset + 4].try_into().unwrap())
+}
+
+/// Reads a 64 bit value stored as two 32 bit halves, low half first
+fn read_u64(entry: &[u8], offset: usize) -> u64 {
+    read_u32(entry, offset) as u64 | (read_u32(entry, offset + 4) as u64) << 32
+}
+
+/// Apic id of the cpu this runs on, the full x2apic id where the cpu reports one
+fn boot_apic_id() -> u32 {
+    #[allow(unused_unsafe)]
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf >= 0xb {
+        #[allow(unused_unsafe)]
+        let topology = unsafe { __cpuid_count(0xb, 0) };
+        if topology.ebx != 0 {
+            return topology.edx;
+        }
+    }
+
+    #[allow(unused_unsafe)]
+    let ebx = unsafe { __cpuid(1) }.ebx;
+    ebx >> 24
+}
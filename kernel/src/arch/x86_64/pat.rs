@@ -0,0 +1,59 @@
+//! Page Attribute Table setup.
+//!
+//! The PAT, PCD and PWT bits of a page table entry select one of eight entries of the IA32_PAT
+//! MSR, which holds the actual memory type. The layout programmed here matches the power-on
+//! default for the first four entries, so mappings made before [`init`] keep their meaning.
+
+use core::arch::{asm, x86_64::__cpuid};
+
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::model_specific::Msr,
+};
+
+const IA32_PAT: u32 = 0x277;
+
+const UNCACHED: u64 = 0x00;
+const WRITE_COMBINING: u64 = 0x01;
+const WRITE_THROUGH: u64 = 0x04;
+const WRITE_PROTECTED: u64 = 0x05;
+const WRITE_BACK: u64 = 0x06;
+const UNCACHED_MINUS: u64 = 0x07;
+
+/// Memory type of every entry, indexed by `PAT << 2 | PCD << 1 | PWT`
+const LAYOUT: [u64; 8] = [
+    WRITE_BACK,
+    WRITE_THROUGH,
+    UNCACHED_MINUS,
+    UNCACHED,
+    WRITE_PROTECTED,
+    WRITE_COMBINING,
+    UNCACHED_MINUS,
+    UNCACHED,
+];
+
+pub fn init() {
+    assert!(
+        supports_pat(),
+        "cpu does not support the page attribute table"
+    );
+
+    let value = LAYOUT
+        .iter()
+        .enumerate()
+        .fold(0, |value, (i, memory_type)| value | memory_type << (i * 8));
+
+    interrupts::without_interrupts(|| unsafe {
+        // no stale cache lines or tlb entries may survive with the old memory type
+        asm!("wbinvd", options(nostack, preserves_flags));
+        Msr::new(IA32_PAT).write(value);
+        asm!("wbinvd", options(nostack, preserves_flags));
+        tlb::flush_all();
+    });
+}
+
+fn supports_pat() -> bool {
+    #[allow(unused_unsafe)]
+    let edx = unsafe { __cpuid(1) }.edx;
+    edx & (1 << 16) != 0
+}
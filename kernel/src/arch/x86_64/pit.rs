@@ -0,0 +1,119 @@
+//! The legacy programmable interval timer.
+//!
+//! Channel 2 is a reference clock for calibrating other timers. Its gate is controlled through
+//! port 0x61 and its output can be read back from there, so no interrupt is needed. Channel 0
+//! can be started as a free running counter for machines without a better clock source, its
+//! interrupt stays masked together with the 8259 PICs.
+
+use core::hint;
+
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+/// Frequency the counters run at, in Hz
+pub const FREQUENCY: u64 = 1_193_182;
+/// Longest wait a single countdown of the 16 bit counter allows
+pub const MAX_WAIT_MICROS: u64 = u16::MAX as u64 * 1_000_000 / FREQUENCY;
+
+const CHANNEL_0_DATA: u16 = 0x40;
+const CHANNEL_2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Gate of channel 2, the speaker and the output of channel 2
+const CONTROL: u16 = 0x61;
+
+/// Channel 2, low byte then high byte, interrupt on terminal count, binary
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+/// Channel 0, low byte then high byte, rate generator, binary
+const CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+/// Channel 0, latch the current count
+const CHANNEL_0_LATCH: u8 = 0b0000_0000;
+
+const GATE: u8 = 1 << 0;
+const SPEAKER: u8 = 1 << 1;
+const OUTPUT: u8 = 1 << 5;
+
+static COUNTER: Mutex<Option<Counter>> = Mutex::new(None);
+
+/// Channel 0 counting down from 65536 over and over, extended to 64 bits
+struct Counter {
+    last: u16,
+    total: u64,
+}
+
+/// Busy waits for `micros` microseconds, at most [`MAX_WAIT_MICROS`]
+pub fn wait(micros: u64) {
+    assert!(
+        micros <= MAX_WAIT_MICROS,
+        "pit wait of {micros} us is too long"
+    );
+
+    let count = (FREQUENCY * micros / 1_000_000) as u16;
+    let mut control = Port::<u8>::new(CONTROL);
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL_2_DATA);
+
+    unsafe {
+        // counting starts once the gate goes up again
+        let value = control.read() & !(GATE | SPEAKER);
+        control.write(value);
+
+        command.write(CHANNEL_2_ONE_SHOT);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        control.write(value | GATE);
+
+        while control.read() & OUTPUT == 0 {
+            hint::spin_loop();
+        }
+
+        control.write(value);
+    }
+}
+
+/// Starts channel 0 as a free running counter for [`count`]
+pub fn start_counter() {
+    interrupts::without_interrupts(|| {
+        let mut counter = COUNTER.lock();
+        if counter.is_some() {
+            return;
+        }
+
+        let mut command = Port::<u8>::new(COMMAND);
+        let mut data = Port::<u8>::new(CHANNEL_0_DATA);
+
+        unsafe {
+            // a reload value of 0 counts 65536 ticks
+            command.write(CHANNEL_0_RATE_GENERATOR);
+            data.write(0);
+            data.write(0);
+        }
+
+        *counter = Some(Counter { last: 0, total: 0 });
+    });
+}
+
+/// Ticks of channel 0 since [`start_counter`], `None` if it wasn't started. The counter wraps
+/// every 55 ms, so it has to be read at least that often to stay correct.
+pub fn count() -> Option<u64> {
+    interrupts::without_interrupts(|| {
+        let mut counter = COUNTER.lock();
+        let counter = counter.as_mut()?;
+
+        let mut command = Port::<u8>::new(COMMAND);
+        let mut data = Port::<u8>::new(CHANNEL_0_DATA);
+
+        let current = unsafe {
+            command.write(CHANNEL_0_LATCH);
+            let low = data.read();
+            let high = data.read();
+            u16::from_le_bytes([low, high])
+        };
+
+        // the counter counts down
+        counter.total += counter.last.wrapping_sub(current) as u64;
+        counter.last = current;
+
+        Some(counter.total)
+    })
+}
@@ -0,0 +1,320 @@
+//! The CMOS real time clock.
+//!
+//! The rtc keeps the calendar time while the machine is off. Its registers are read through the
+//! CMOS index and data ports, in BCD or binary and with a 12 or 24 hour clock depending on status
+//! register B. The century lives in the register the FADT names, if any. The rtc can also raise
+//! periodic and alarm interrupts on isa irq 8.
+
+use acpi::{AcpiTables, sdt::fadt::Fadt};
+use spin::{Mutex, Once};
+use x86_64::instructions::{interrupts, port::Port};
+
+use super::{acpi::AcpiHandler, apic, idt::InterruptIndex};
+use crate::time::realtime::DateTime;
+
+const INDEX: u16 = 0x70;
+const DATA: u16 = 0x71;
+
+// registers
+const SECONDS: u8 = 0x00;
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const HOURS_ALARM: u8 = 0x05;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+// status a
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const RATE_MASK: u8 = 0x0f;
+
+// status b
+const HOURS_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+const ALARM_INTERRUPT: u8 = 1 << 5;
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+
+// status c
+const ALARM_FIRED: u8 = 1 << 5;
+const PERIODIC_FIRED: u8 = 1 << 6;
+
+const HOUR_PM: u8 = 1 << 7;
+
+/// Frequency of the rtc's oscillator, periodic rates divide it by powers of two
+const BASE_FREQUENCY: u32 = 32768;
+
+const IRQ: u8 = 8;
+
+/// Years without a century register are taken to be in this century
+const DEFAULT_CENTURY: u16 = 20;
+
+/// Serializes register accesses, selecting a register and accessing it are separate steps
+static CMOS: Mutex<()> = Mutex::new(());
+/// The cmos register holding the century, if the fadt names one
+static CENTURY_REGISTER: Once<Option<u8>> = Once::new();
+static PERIODIC_HANDLER: Mutex<Option<RtcHandler>> = Mutex::new(None);
+static ALARM_HANDLER: Mutex<Option<RtcHandler>> = Mutex::new(None);
+
+/// Called from the interrupt handler
+pub type RtcHandler = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    /// Periodic interrupts run at powers of two between 2 and 8192 Hz
+    InvalidFrequency(u32),
+    /// The alarm time isn't a valid time of day
+    InvalidTime,
+}
+
+/// Finds the century register and routes the rtc interrupt, which stays off until asked for
+///
+/// # Safety
+///
+/// Must only be called once, with the tables of the running machine, after the io apic has been
+/// initialized
+pub unsafe fn init(tables: &AcpiTables<AcpiHandler>) {
+    let century = tables
+        .find_table::<Fadt>()
+        .map(|fadt| fadt.century)
+        .filter(|&register| register != 0);
+    CENTURY_REGISTER.call_once(|| century);
+
+    interrupts::without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        unsafe {
+            let status_b = read_register(STATUS_B);
+            write_register(STATUS_B, status_b & !(ALARM_INTERRUPT | PERIODIC_INTERRUPT));
+            // a pending interrupt blocks further ones until status c is read
+            read_register(STATUS_C);
+        }
+    });
+
+    apic::route_isa_irq(IRQ, InterruptIndex::Rtc).expect("failed to route the rtc interrupt");
+}
+
+/// Reads the current date and time, which the rtc keeps in UTC
+pub fn read() -> DateTime {
+    interrupts::without_interrupts(|| {
+        let _cmos = CMOS.lock();
+
+        // the registers are only consistent outside of an update, reading until two reads match
+        // makes sure none started in between
+        let mut time = unsafe { read_raw() };
+        loop {
+            let again = unsafe { read_raw() };
+            if again == time {
+                break;
+            }
+            time = again;
+        }
+
+        let status_b = unsafe { read_register(STATUS_B) };
+        time.decode(status_b)
+    })
+}
+
+/// Calls `handler` `frequency` times per second, replacing the previous periodic handler
+pub fn enable_periodic(frequency: u32, handler: RtcHandler) -> Result<(), RtcError> {
+    if !frequency.is_power_of_two() || !(2..=8192).contains(&frequency) {
+        return Err(RtcError::InvalidFrequency(frequency));
+    }
+
+    // frequency = BASE_FREQUENCY >> (rate - 1)
+    let rate = (BASE_FREQUENCY / frequency).trailing_zeros() as u8 + 1;
+
+    interrupts::without_interrupts(|| {
+        *PERIODIC_HANDLER.lock() = Some(handler);
+
+        let _cmos = CMOS.lock();
+        unsafe {
+            let status_a = read_register(STATUS_A);
+            write_register(STATUS_A, (status_a & !RATE_MASK) | rate);
+            let status_b = read_register(STATUS_B);
+            write_register(STATUS_B, status_b | PERIODIC_INTERRUPT);
+        }
+    });
+
+    Ok(())
+}
+
+pub fn disable_periodic() {
+    interrupts::without_interrupts(|| {
+        clear_interrupt(PERIODIC_INTERRUPT);
+        *PERIODIC_HANDLER.lock() = None;
+    });
+}
+
+/// Calls `handler` every day at `hour`:`minute`:`second` UTC, replacing the previous alarm
+pub fn set_alarm(hour: u8, minute: u8, second: u8, handler: RtcHandler) -> Result<(), RtcError> {
+    if hour > 23 || minute > 59 || second > 59 {
+        return Err(RtcError::InvalidTime);
+    }
+
+    interrupts::without_interrupts(|| {
+        *ALARM_HANDLER.lock() = Some(handler);
+
+        let _cmos = CMOS.lock();
+        unsafe {
+            let status_b = read_register(STATUS_B);
+            write_register(SECONDS_ALARM, encode(second, status_b));
+            write_register(MINUTES_ALARM, encode(minute, status_b));
+            write_register(HOURS_ALARM, encode_hour(hour, status_b));
+            write_register(STATUS_B, status_b | ALARM_INTERRUPT);
+        }
+    });
+
+    Ok(())
+}
+
+pub fn clear_alarm() {
+    interrupts::without_interrupts(|| {
+        clear_interrupt(ALARM_INTERRUPT);
+        *ALARM_HANDLER.lock() = None;
+    });
+}
+
+/// Acknowledges the rtc interrupt and runs the handlers of the events that caused it, called from
+/// the interrupt handler of [`InterruptIndex::Rtc`]
+pub(crate) fn handle_interrupt() {
+    let status_c = {
+        let _cmos = CMOS.lock();
+        unsafe { read_register(STATUS_C) }
+    };
+
+    if status_c & PERIODIC_FIRED != 0
+        && let Some(handler) = *PERIODIC_HANDLER.lock()
+    {
+        handler();
+    }
+
+    if status_c & ALARM_FIRED != 0
+        && let Some(handler) = *ALARM_HANDLER.lock()
+    {
+        handler();
+    }
+}
+
+/// The time registers as the rtc stores them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+impl RawTime {
+    fn decode(self, status_b: u8) -> DateTime {
+        let binary = status_b & BINARY != 0;
+        let decode = |value: u8| if binary { value } else { from_bcd(value) };
+
+        let mut hour = decode(self.hour & !HOUR_PM);
+        if status_b & HOURS_24 == 0 {
+            // 12 am is midnight, 12 pm is noon
+            hour %= 12;
+            if self.hour & HOUR_PM != 0 {
+                hour += 12;
+            }
+        }
+
+        let century = match CENTURY_REGISTER.get().copied().flatten() {
+            Some(_) => decode(self.century) as u16,
+            None => DEFAULT_CENTURY,
+        };
+
+        DateTime {
+            year: century * 100 + decode(self.year) as u16,
+            month: decode(self.month),
+            day: decode(self.day),
+            hour,
+            minute: decode(self.minute),
+            second: decode(self.second),
+        }
+    }
+}
+
+/// # Safety
+///
+/// The caller must hold the [`CMOS`] lock
+unsafe fn read_raw() -> RawTime {
+    unsafe {
+        while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+
+        RawTime {
+            second: read_register(SECONDS),
+            minute: read_register(MINUTES),
+            hour: read_register(HOURS),
+            day: read_register(DAY),
+            month: read_register(MONTH),
+            year: read_register(YEAR),
+            century: match CENTURY_REGISTER.get().copied().flatten() {
+                Some(register) => read_register(register),
+                None => 0,
+            },
+        }
+    }
+}
+
+fn clear_interrupt(interrupt: u8) {
+    let _cmos = CMOS.lock();
+    unsafe {
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b & !interrupt);
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+fn encode(value: u8, status_b: u8) -> u8 {
+    if status_b & BINARY != 0 {
+        value
+    } else {
+        ((value / 10) << 4) | (value % 10)
+    }
+}
+
+fn encode_hour(hour: u8, status_b: u8) -> u8 {
+    if status_b & HOURS_24 != 0 {
+        return encode(hour, status_b);
+    }
+
+    let pm = if hour >= 12 { HOUR_PM } else { 0 };
+    let hour = match hour % 12 {
+        0 => 12,
+        hour => hour,
+    };
+
+    encode(hour, status_b) | pm
+}
+
+/// # Safety
+///
+/// The caller must hold the [`CMOS`] lock
+unsafe fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(INDEX).write(register);
+        Port::<u8>::new(DATA).read()
+    }
+}
+
+/// # Safety
+///
+/// The caller must hold the [`CMOS`] lock and the write must not corrupt the firmware's settings
+unsafe fn write_register(register: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(INDEX).write(register);
+        Port::<u8>::new(DATA).write(value);
+    }
+}
@@ -0,0 +1,67 @@
+//! The time stamp counter.
+//!
+//! Only an invariant tsc ticks at a constant rate through frequency and power state changes, older
+//! ones are useless as a clock. The frequency comes from cpuid when the cpu reports it and is
+//! measured against the hpet or the pit otherwise.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdtsc};
+
+use spin::Once;
+use x86_64::instructions::interrupts;
+
+use super::{CALIBRATION_MICROS, hpet, pit};
+
+static FREQUENCY: Once<u64> = Once::new();
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+pub fn is_invariant() -> bool {
+    #[allow(unused_unsafe)]
+    let max_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_leaf < 0x8000_0007 {
+        return false;
+    }
+
+    #[allow(unused_unsafe)]
+    let edx = unsafe { __cpuid(0x8000_0007) }.edx;
+    edx & (1 << 8) != 0
+}
+
+/// Ticks per second, measured on first use
+pub fn frequency() -> u64 {
+    *FREQUENCY.call_once(|| {
+        cpuid_frequency()
+            .or_else(|| hpet::calibrate_tsc().ok())
+            .unwrap_or_else(calibrate_with_pit)
+    })
+}
+
+/// The frequency from the core crystal clock leaf, which not every cpu fills in
+fn cpuid_frequency() -> Option<u64> {
+    #[allow(unused_unsafe)]
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf < 0x15 {
+        return None;
+    }
+
+    #[allow(unused_unsafe)]
+    let leaf = unsafe { __cpuid_count(0x15, 0) };
+    let (denominator, numerator, crystal) = (leaf.eax, leaf.ebx, leaf.ecx);
+    if denominator == 0 || numerator == 0 || crystal == 0 {
+        return None;
+    }
+
+    Some(crystal as u64 * numerator as u64 / denominator as u64)
+}
+
+fn calibrate_with_pit() -> u64 {
+    let elapsed = interrupts::without_interrupts(|| {
+        let start = read();
+        pit::wait(CALIBRATION_MICROS);
+        read() - start
+    });
+
+    elapsed * 1_000_000 / CALIBRATION_MICROS
+}
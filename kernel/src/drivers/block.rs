@@ -0,0 +1,88 @@
+//! Block devices.
+//!
+//! Drivers for disks implement [`BlockDevice`]. Other parts of the kernel, like swap, only access
+//! storage through that trait, usually through a [`Partition`] of a device.
+
+use alloc::sync::Arc;
+
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request reaches past the last sector
+    OutOfRange,
+    /// The buffer isn't a whole number of sectors
+    UnalignedBuffer,
+    /// The device reported an error
+    Io,
+}
+
+/// Storage that is read and written in whole sectors.
+///
+/// Requests complete before the methods return and must work with interrupts disabled, as the
+/// memory manager issues them while holding the page table locks of user address spaces. It
+/// never holds the frame allocator or the heap while doing so, so implementations may allocate
+/// memory, like DMA buffers, but must not touch user memory.
+pub trait BlockDevice: Send + Sync {
+    fn sector_count(&self) -> u64;
+
+    /// Reads `buf.len() / SECTOR_SIZE` sectors starting at `sector`
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf.len() / SECTOR_SIZE` sectors starting at `sector`
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError>;
+}
+
+/// Consecutive sectors of a device, addressed from 0
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    start: u64,
+    sectors: u64,
+}
+
+impl Partition {
+    pub fn new(device: Arc<dyn BlockDevice>, start: u64, sectors: u64) -> Result<Self, BlockError> {
+        let end = start.checked_add(sectors).ok_or(BlockError::OutOfRange)?;
+        if end > device.sector_count() {
+            return Err(BlockError::OutOfRange);
+        }
+
+        Ok(Self {
+            device,
+            start,
+            sectors,
+        })
+    }
+
+    /// The device sector `sector` of the partition is at, as long as `len` bytes fit from there
+    fn translate(&self, sector: u64, len: usize) -> Result<u64, BlockError> {
+        if !len.is_multiple_of(SECTOR_SIZE) {
+            return Err(BlockError::UnalignedBuffer);
+        }
+
+        let end = sector
+            .checked_add((len / SECTOR_SIZE) as u64)
+            .ok_or(BlockError::OutOfRange)?;
+        if end > self.sectors {
+            return Err(BlockError::OutOfRange);
+        }
+
+        Ok(self.start + sector)
+    }
+}
+
+impl BlockDevice for Partition {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let sector = self.translate(sector, buf.len())?;
+        self.device.read(sector, buf)
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let sector = self.translate(sector, buf.len())?;
+        self.device.write(sector, buf)
+    }
+}
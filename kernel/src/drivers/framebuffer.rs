@@ -6,8 +6,12 @@ use noto_sans_mono_bitmap::{
     FontWeight, RasterHeight, RasterizedChar, get_raster, get_raster_width,
 };
 use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
 
-use crate::common::color::Color;
+use crate::{
+    common::color::Color,
+    mem::{self, mmio, vmm::CacheMode},
+};
 
 #[used]
 #[unsafe(link_section = ".requests")]
@@ -27,6 +31,16 @@ pub fn init() {
         Mutex::new(FrameBufferWriter::new(fb))
     });
 }
+
+/// Moves the writer over to a write-combining mapping of the framebuffer, which makes drawing a lot
+/// faster than through the mapping set up by limine. Needs the PAT and the memory manager.
+pub fn enable_write_combining() {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.get().expect("framebuffer not initialized").lock();
+        writer.remap(CacheMode::WriteCombining);
+    })
+}
+
 /// Additional vertical space between lines
 const LINE_SPACING: usize = 2;
 /// Additional horizontal space between characters.
@@ -68,7 +82,10 @@ fn get_char_raster(c: char) -> RasterizedChar {
 /// Allows logging text to a pixel-based framebuffer. This is the only framebuffer for the program.
 pub struct FrameBufferWriter {
     framebuffer: &'static mut [u8],
-    info: Framebuffer<'static>,
+    // copied out of the limine response so that bootloader memory can be reclaimed
+    width: usize,
+    height: usize,
+    pitch: usize,
     x_pos: usize,
     y_pos: usize,
 }
@@ -76,13 +93,16 @@ pub struct FrameBufferWriter {
 impl FrameBufferWriter {
     /// Creates a new logger that uses the given framebuffer.
     pub fn new(framebuffer: Framebuffer<'static>) -> Self {
-        let info = framebuffer;
-
         let mut writer = Self {
             framebuffer: unsafe {
-                slice::from_raw_parts_mut(info.addr(), (info.height() * info.pitch()) as usize)
+                slice::from_raw_parts_mut(
+                    framebuffer.addr(),
+                    (framebuffer.height() * framebuffer.pitch()) as usize,
+                )
             },
-            info,
+            width: framebuffer.width() as usize,
+            height: framebuffer.height() as usize,
+            pitch: framebuffer.pitch() as usize,
             x_pos: 0,
             y_pos: 0,
         };
@@ -92,6 +112,16 @@ impl FrameBufferWriter {
         writer
     }
 
+    fn remap(&mut self, cache_mode: CacheMode) {
+        let len = self.framebuffer.len();
+        // limine maps the framebuffer in its hhdm, which may lie outside of the kernel's
+        let phys = mem::paging::bootloader_virt_to_phys(self.framebuffer.as_ptr());
+        let virt =
+            mmio::ioremap(phys, len as u64, cache_mode).expect("failed to map the framebuffer");
+
+        self.framebuffer = unsafe { slice::from_raw_parts_mut(virt.as_mut_ptr(), len) };
+    }
+
     fn newline(&mut self) {
         self.y_pos += font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
         self.carriage_return()
@@ -109,11 +139,11 @@ impl FrameBufferWriter {
     }
 
     fn width(&self) -> usize {
-        self.info.width() as usize
+        self.width
     }
 
     fn height(&self) -> usize {
-        self.info.height() as usize
+        self.height
     }
 
     /// Writes a single char to the framebuffer. Takes care of special control characters, such as
@@ -149,7 +179,7 @@ impl FrameBufferWriter {
     }
 
     fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
-        let pixel_offset = y * self.info.pitch() as usize + x * 4;
+        let pixel_offset = y * self.pitch + x * 4;
         let color = match Color::Rgb(intensity, intensity, intensity) {
             Color::Rgb(r, g, b) => [r, g, b, 0],
         };
@@ -5,6 +5,7 @@ use crate::{
     tasks::executor::{ASYNC_EXECUTOR, Task},
 };
 
+pub mod block;
 pub mod framebuffer;
 pub(crate) mod keyboard;
 mod serial_monitor;
@@ -15,6 +16,8 @@ pub fn init_stdout() {
 }
 
 pub fn init() {
+    framebuffer::enable_write_combining();
+
     let mut executor = unsafe { ASYNC_EXECUTOR.get_unchecked() }.lock();
     executor.spawn(Task::new(print_keypresses()));
 }
@@ -36,6 +39,18 @@ macro_rules! warning {
     ($($arg:tt)*) => ($crate::print!("WARNING: {}\n", format_args!($($arg)*)));
 }
 
+/// Prints to the serial port only, for output that would flood the screen
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::drivers::_serial_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
@@ -58,3 +73,19 @@ pub fn _print(args: fmt::Arguments) {
             .unwrap();
     })
 }
+
+#[doc(hidden)]
+pub fn _serial_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        serial_monitor::WRITER
+            .get()
+            .expect("serial monitor not initialized")
+            .lock()
+            .write_fmt(args)
+            .unwrap();
+    })
+}
@@ -1,6 +1,7 @@
 #![no_std]
 #![no_main]
 #![feature(abi_x86_interrupt)]
+#![feature(allocator_api)]
 extern crate alloc;
 
 pub mod arch;
@@ -8,6 +9,7 @@ pub mod common;
 pub mod drivers;
 pub mod mem;
 pub mod tasks;
+pub mod time;
 
 pub fn hlt_loop() -> ! {
     loop {
@@ -3,7 +3,13 @@
 
 extern crate alloc;
 
-use kernel::{tasks::executor::ASYNC_EXECUTOR, *};
+use alloc::boxed::Box;
+
+use kernel::{
+    mem::stack::{KERNEL_STACK_SIZE, KernelStack},
+    tasks::executor::ASYNC_EXECUTOR,
+    *,
+};
 use limine::{
     BaseRevision,
     request::{RequestsEndMarker, RequestsStartMarker},
@@ -26,10 +32,27 @@ unsafe extern "C" fn kmain() -> ! {
 
     drivers::init_stdout();
     mem::init();
+
+    // the boot stack has no guard page and lives in bootloader reclaimable memory
+    let stack = KernelStack::new(KERNEL_STACK_SIZE).expect("failed to allocate the kernel stack");
+    unsafe { Box::leak(Box::new(stack)).switch_to(kmain_on_kernel_stack) }
+}
+
+extern "C" fn kmain_on_kernel_stack() -> ! {
     arch::init();
+    time::init();
     tasks::executor::init();
     drivers::init();
 
+    unsafe {
+        // everything needed from the acpi tables and limine responses has been copied out by now
+        mem::reclaim::reclaim_acpi_memory();
+        mem::reclaim::reclaim_bootloader_memory();
+    }
+
+    // the page tables were copied during the reclaim and drivers mapped their registers
+    mem::kernel_image::audit();
+
     unsafe { ASYNC_EXECUTOR.get_unchecked().lock().run() }
 
     println!("hello, world!");
@@ -0,0 +1,789 @@
+//! Per-process address spaces.
+//!
+//! Every address space has a PML4 of its own. The lower half belongs to the process, the upper
+//! half entries point to the same tables as the kernel PML4, so kernel mappings show up in every
+//! address space. That only works as long as the kernel never adds upper half PML4 entries, which
+//! is why [`init`] allocates all of them up front. With 5-level paging CR3 points to a PML5 of the
+//! address space instead, which reaches the kernel PML4 directly, and the PML4 of the address
+//! space only holds the lower half, see [`super::paging`].
+//!
+//! [`AddressSpace::fork`] doesn't copy any memory. Both address spaces map the same frames, with
+//! writable pages made read-only and marked [`COPY_ON_WRITE`]. The first write to such a page
+//! faults and gives the writer a copy of its own, or the frame itself if nobody else maps it
+//! anymore. Frames are reference counted, so they are freed once the last mapping is gone.
+//!
+//! Every address space is registered so that [`super::swap`] can find cold pages in it. Swapped
+//! out pages are read back by the fault handler and by [`AddressSpace::write`].
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{ops::Range, ptr};
+
+use spin::Mutex;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::{interrupts, tlb},
+    registers::control::Cr3,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, OffsetPageTable, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB, Translate, page_table::PageTableEntry,
+    },
+};
+
+use super::{
+    FRAME_ALLOCATOR, MAPPER,
+    frame_allocator::KernelFrameAllocator,
+    paging, swap,
+    vma::{Backing, Region, RegionKind, RegionTable, VmaError},
+    vmm::{self, VmmError},
+};
+
+/// The first page stays unmapped so that null pointers fault
+const USER_START: u64 = 0x1000;
+const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// PML4 entries covering the kernel half
+pub(super) const KERNEL_ENTRIES: Range<usize> = 256..512;
+
+/// Software bit marking a page that is shared read-only until somebody writes to it
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+static ACTIVE: Mutex<Option<Arc<AddressSpace>>> = Mutex::new(None);
+/// Every live address space. Taken before the regions lock of any of them.
+static SPACES: Mutex<Vec<SpaceEntry>> = Mutex::new(Vec::new());
+
+struct SpaceEntry {
+    root: PhysFrame,
+    pml4: PhysFrame,
+    regions: Arc<Mutex<RegionTable>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpaceError {
+    Vma(VmaError),
+    Vmm(VmmError),
+    /// The address isn't inside a region of the address space
+    NotReserved(VirtAddr),
+}
+
+impl From<VmaError> for AddressSpaceError {
+    fn from(err: VmaError) -> Self {
+        AddressSpaceError::Vma(err)
+    }
+}
+
+impl From<VmmError> for AddressSpaceError {
+    fn from(err: VmmError) -> Self {
+        AddressSpaceError::Vmm(err)
+    }
+}
+
+/// Gives every kernel half PML4 entry a table, so that address spaces created later share all
+/// kernel mappings
+pub(super) fn init() {
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.get().expect("mapper not initialized").lock();
+        let mut frame_allocator = FRAME_ALLOCATOR
+            .get()
+            .expect("frame allocator not initialized")
+            .lock();
+
+        let level_4_table = mapper.level_4_table_mut();
+        for i in KERNEL_ENTRIES {
+            let entry = &mut level_4_table[i];
+            if entry.flags().contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+
+            let frame = allocate_table(&mut frame_allocator)
+                .expect("out of memory while allocating kernel page tables");
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    })
+}
+
+pub fn is_user_address(addr: VirtAddr) -> bool {
+    addr.as_u64() < USER_END
+}
+
+/// The address space whose lower half is currently mapped, `None` while only the kernel runs
+pub fn active() -> Option<Arc<AddressSpace>> {
+    interrupts::without_interrupts(|| ACTIVE.try_lock()?.clone())
+}
+
+/// Switches back to the kernel page tables, leaving the lower half empty
+pub fn activate_kernel() {
+    interrupts::without_interrupts(|| {
+        let mut active = ACTIVE.lock();
+        unsafe { paging::switch_root(paging::kernel_root()) };
+        *active = None;
+    })
+}
+
+pub struct AddressSpace {
+    /// What CR3 points to, the same frame as `pml4` unless 5-level paging is enabled
+    root: PhysFrame,
+    pml4: PhysFrame,
+    /// Also guards the lower half page tables
+    regions: Arc<Mutex<RegionTable>>,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty lower half.
+    ///
+    /// Bootloader memory must have been reclaimed already, the kernel page tables are replaced
+    /// during that.
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        let (root, pml4) = interrupts::without_interrupts(|| {
+            let mapper = MAPPER.get().expect("mapper not initialized").lock();
+            let mut frame_allocator = FRAME_ALLOCATOR
+                .get()
+                .expect("frame allocator not initialized")
+                .lock();
+
+            let pml4 = allocate_table(&mut frame_allocator).ok_or(VmmError::OutOfMemory)?;
+            let table = unsafe { table_at(pml4.start_address()) };
+
+            if paging::pml4_maps_kernel() {
+                for i in KERNEL_ENTRIES {
+                    table[i] = mapper.level_4_table()[i].clone();
+                }
+            }
+
+            let Some(root) = paging::new_root(pml4, &mut frame_allocator) else {
+                unsafe { frame_allocator.deallocate_frame(pml4) };
+                return Err(VmmError::OutOfMemory.into());
+            };
+
+            Ok::<_, AddressSpaceError>((root, pml4))
+        })?;
+
+        let regions = Arc::new(Mutex::new(RegionTable::new(
+            VirtAddr::new(USER_START),
+            VirtAddr::new(USER_END),
+        )));
+
+        interrupts::without_interrupts(|| {
+            SPACES.lock().push(SpaceEntry {
+                root,
+                pml4,
+                regions: regions.clone(),
+            })
+        });
+
+        Ok(Self {
+            root,
+            pml4,
+            regions,
+        })
+    }
+
+    pub fn pml4(&self) -> PhysFrame {
+        self.pml4
+    }
+
+    /// Creates a copy of this address space that shares all frames with it. Writable pages become
+    /// copy-on-write in both address spaces.
+    pub fn fork(&self) -> Result<Self, AddressSpaceError> {
+        let child = Self::new()?;
+
+        self.with_tables(|regions, mapper, frame_allocator| {
+            *child.regions.lock() = regions.clone();
+
+            let child_pml4 = unsafe { table_at(child.pml4.start_address()) };
+            let result = unsafe {
+                share_user_pages(
+                    mapper.level_4_table_mut(),
+                    4,
+                    0,
+                    child_pml4,
+                    frame_allocator,
+                )
+            };
+
+            // stale entries would still let this address space write to the shared frames
+            if Cr3::read().0 == self.root {
+                tlb::flush_all();
+            }
+
+            result
+        })?;
+
+        Ok(child)
+    }
+
+    /// Loads the page tables of this address space and makes it the one faults are resolved in
+    pub fn activate(self: &Arc<Self>) {
+        interrupts::without_interrupts(|| {
+            let mut active = ACTIVE.lock();
+            unsafe { paging::switch_root(self.root) };
+            *active = Some(self.clone());
+        })
+    }
+
+    /// Reserves `len` bytes of user memory at `virt`, backed by zeroed frames on first access
+    pub fn allocate(
+        &self,
+        virt: VirtAddr,
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+        interrupts::without_interrupts(|| {
+            self.regions
+                .lock()
+                .reserve_at(virt, len, RegionKind::User, Backing::Demand(flags))
+        })?;
+
+        Ok(())
+    }
+
+    /// Releases the region starting at `virt` together with the frames behind it
+    pub fn free(&self, virt: VirtAddr) -> Result<(), AddressSpaceError> {
+        self.with_tables(|regions, mapper, frame_allocator| {
+            let region = regions.release(virt)?;
+            vmm::unmap_locked(
+                mapper,
+                frame_allocator,
+                region.start,
+                region.size,
+                true,
+                true,
+            )?;
+            free_swap_entries(mapper.level_4_table_mut(), region.start, region.size);
+
+            Ok(())
+        })
+    }
+
+    /// Copies `data` to `virt`, which doesn't need to be the active address space
+    pub fn write(&self, virt: VirtAddr, data: &[u8]) -> Result<(), AddressSpaceError> {
+        self.with_regions(|regions, mapper| {
+            let mut offset = 0;
+
+            while offset < data.len() {
+                let addr = virt + offset as u64;
+                let region = regions
+                    .find(addr)
+                    .ok_or(AddressSpaceError::NotReserved(addr))?;
+
+                if mapper.translate_addr(addr).is_some() {
+                    // the data must not show up in the other owners of the frame
+                    with_frame_allocator(|frame_allocator| unsafe {
+                        unshare_page(mapper.level_4_table_mut(), frame_allocator, addr)
+                    })?;
+                } else {
+                    let Backing::Demand(flags) = region.backing else {
+                        return Err(VmmError::NotMapped(addr).into());
+                    };
+                    back_page(mapper.level_4_table_mut(), addr, flags)?;
+                }
+                let phys = mapper.translate_addr(addr).expect("page is mapped");
+
+                let len = (Size4KiB::SIZE - addr.as_u64() % Size4KiB::SIZE)
+                    .min((data.len() - offset) as u64) as usize;
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        data[offset..].as_ptr(),
+                        super::phys_to_virt(phys).as_mut_ptr(),
+                        len,
+                    );
+                }
+
+                offset += len;
+            }
+
+            Ok(())
+        })
+    }
+
+    pub fn find_region(&self, addr: VirtAddr) -> Option<Region> {
+        interrupts::without_interrupts(|| self.regions.lock().find(addr))
+    }
+
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.with_tables(|_, mapper, _| mapper.translate_addr(addr))
+    }
+
+    /// Backs the page at `page` with its contents from swap or a zeroed frame, for the page fault
+    /// handler
+    pub(super) fn commit_page(
+        &self,
+        page: VirtAddr,
+        flags: PageTableFlags,
+    ) -> Result<(), VmmError> {
+        self.with_regions(|_, mapper| back_page(mapper.level_4_table_mut(), page, flags))
+    }
+
+    pub(super) fn is_copy_on_write(&self, page: VirtAddr) -> bool {
+        self.with_tables(|_, mapper, _| {
+            leaf_entry(mapper.level_4_table_mut(), page)
+                .is_some_and(|entry| entry.flags().contains(COPY_ON_WRITE))
+        })
+    }
+
+    /// Resolves a write fault on a copy-on-write page, for the page fault handler
+    pub(super) fn unshare_page(&self, page: VirtAddr) -> Result<(), VmmError> {
+        self.with_tables(|_, mapper, frame_allocator| unsafe {
+            unshare_page(mapper.level_4_table_mut(), frame_allocator, page)
+        })
+    }
+
+    pub(super) fn is_locked(&self) -> bool {
+        self.regions.is_locked()
+    }
+
+    fn with_tables<R>(
+        &self,
+        f: impl FnOnce(&mut RegionTable, &mut OffsetPageTable, &mut KernelFrameAllocator) -> R,
+    ) -> R {
+        interrupts::without_interrupts(|| {
+            let mut regions = self.regions.lock();
+            let mut mapper = unsafe { self.mapper() };
+            let mut frame_allocator = FRAME_ALLOCATOR
+                .get()
+                .expect("frame allocator not initialized")
+                .lock();
+
+            f(&mut regions, &mut mapper, &mut frame_allocator)
+        })
+    }
+
+    /// Like [`Self::with_tables`], but leaves taking the frame allocator to `f`, so that it can do
+    /// block I/O without holding it
+    fn with_regions<R>(&self, f: impl FnOnce(&mut RegionTable, &mut OffsetPageTable) -> R) -> R {
+        interrupts::without_interrupts(|| {
+            let mut regions = self.regions.lock();
+            let mut mapper = unsafe { self.mapper() };
+
+            f(&mut regions, &mut mapper)
+        })
+    }
+
+    /// # Safety
+    ///
+    /// The caller must hold the regions lock, or otherwise be the only one using the tables
+    unsafe fn mapper(&self) -> OffsetPageTable<'_> {
+        unsafe {
+            OffsetPageTable::new(
+                table_at(self.pml4.start_address()),
+                VirtAddr::new(super::hhdm_offset()),
+            )
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // the active address space is referenced by ACTIVE, so this one isn't loaded
+        interrupts::without_interrupts(|| {
+            // once unregistered the swapper can't be looking at the tables anymore
+            SPACES.lock().retain(|space| space.pml4 != self.pml4);
+
+            let mut frame_allocator = FRAME_ALLOCATOR
+                .get()
+                .expect("frame allocator not initialized")
+                .lock();
+
+            let pml4 = unsafe { table_at(self.pml4.start_address()) };
+            for entry in pml4.iter().take(KERNEL_ENTRIES.start) {
+                if entry.flags().contains(PageTableFlags::PRESENT) {
+                    unsafe { free_user_table(entry.addr(), 3, &mut frame_allocator) };
+                }
+            }
+
+            unsafe {
+                paging::free_root(self.root, &mut frame_allocator);
+                frame_allocator.deallocate_frame(self.pml4);
+            }
+        })
+    }
+}
+
+/// Maps every user page below `table` into the hierarchy under `child` as well, taking a reference
+/// to each frame. Writable pages become copy-on-write in both.
+///
+/// # Safety
+///
+/// `table` must be a table of the given `level` in a lower half hierarchy that covers `base`,
+/// `child` the level 4 table of another address space whose tables are locked
+unsafe fn share_user_pages(
+    table: &mut PageTable,
+    level: u8,
+    base: u64,
+    child: &mut PageTable,
+    frame_allocator: &mut KernelFrameAllocator,
+) -> Result<(), VmmError> {
+    let entries = if level == 4 {
+        0..KERNEL_ENTRIES.start
+    } else {
+        0..512
+    };
+
+    for i in entries {
+        let entry = &mut table[i];
+        let flags = entry.flags();
+
+        // 9 bits of the address are translated per level
+        let addr = base | (i as u64) << (12 + (level as u64 - 1) * 9);
+
+        if swap::is_swap_entry(entry) {
+            let child_entry = leaf_entry_or_create(child, VirtAddr::new(addr), frame_allocator)?;
+            swap::share_entry(entry);
+            *child_entry = entry.clone();
+            continue;
+        }
+
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        if level > 1 {
+            assert!(
+                !flags.contains(PageTableFlags::HUGE_PAGE),
+                "huge pages in user address spaces are not supported"
+            );
+            unsafe {
+                share_user_pages(
+                    table_at(entry.addr()),
+                    level - 1,
+                    addr,
+                    child,
+                    frame_allocator,
+                )
+            }?;
+            continue;
+        }
+
+        let flags = if flags.contains(PageTableFlags::WRITABLE) {
+            (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+        } else {
+            flags
+        };
+        entry.set_flags(flags);
+
+        // the leaf is set directly, tables made by the mapper would inherit its read-only flags
+        let child_entry = leaf_entry_or_create(child, VirtAddr::new(addr), frame_allocator)?;
+        frame_allocator.share(PhysFrame::containing_address(entry.addr()));
+        child_entry.set_addr(entry.addr(), flags);
+    }
+
+    Ok(())
+}
+
+/// Gives the page at `addr` a frame of its own if it is copy-on-write and makes it writable again.
+/// Other pages are left alone.
+///
+/// # Safety
+///
+/// `pml4` must be the level 4 table of an address space whose tables are locked
+unsafe fn unshare_page(
+    pml4: &mut PageTable,
+    frame_allocator: &mut KernelFrameAllocator,
+    addr: VirtAddr,
+) -> Result<(), VmmError> {
+    let Some(entry) = leaf_entry(pml4, addr) else {
+        return Err(VmmError::NotMapped(addr));
+    };
+
+    let flags = entry.flags();
+    if !flags.contains(COPY_ON_WRITE) {
+        return Ok(());
+    }
+
+    let frame = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+
+    // the last owner takes the frame over instead of copying it
+    if frame_allocator.ref_count(frame) > 1 {
+        let copy: PhysFrame = frame_allocator
+            .allocate_frame()
+            .ok_or(VmmError::OutOfMemory)?;
+
+        unsafe {
+            ptr::copy_nonoverlapping(
+                super::phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                super::phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                Size4KiB::SIZE as usize,
+            );
+        }
+
+        entry.set_addr(copy.start_address(), flags);
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+
+    entry.set_flags((flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE);
+    tlb::flush(addr);
+
+    Ok(())
+}
+
+/// The level 1 entry for `addr` if it maps a page, `None` otherwise
+fn leaf_entry(pml4: &mut PageTable, addr: VirtAddr) -> Option<&mut PageTableEntry> {
+    leaf_slot(pml4, addr).filter(|entry| entry.flags().contains(PageTableFlags::PRESENT))
+}
+
+/// The level 1 entry for `addr`, mapped or not, `None` if a table above it is missing
+fn leaf_slot(pml4: &mut PageTable, addr: VirtAddr) -> Option<&mut PageTableEntry> {
+    let mut table = pml4;
+
+    for index in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+        let flags = table[index].flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = unsafe { table_at(table[index].addr()) };
+    }
+
+    Some(&mut table[addr.p1_index()])
+}
+
+/// Like [`leaf_slot`], but allocates the missing tables above the entry
+fn leaf_entry_or_create<'a>(
+    pml4: &'a mut PageTable,
+    addr: VirtAddr,
+    frame_allocator: &mut KernelFrameAllocator,
+) -> Result<&'a mut PageTableEntry, VmmError> {
+    let mut table = pml4;
+
+    for index in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+        let entry = &mut table[index];
+        if entry.is_unused() {
+            let frame = allocate_table(frame_allocator).ok_or(VmmError::OutOfMemory)?;
+            entry.set_frame(
+                frame,
+                PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::USER_ACCESSIBLE,
+            );
+        }
+        table = unsafe { table_at(entry.addr()) };
+    }
+
+    Ok(&mut table[addr.p1_index()])
+}
+
+/// Maps a frame for a page that isn't present, with the page's contents if it was swapped out.
+/// The tables above it are created writable and user accessible, whatever `flags` says.
+///
+/// Takes the frame allocator itself, the page is read from swap without holding it.
+fn back_page(pml4: &mut PageTable, page: VirtAddr, flags: PageTableFlags) -> Result<(), VmmError> {
+    let (entry, frame) = with_frame_allocator(|frame_allocator| {
+        let entry = leaf_entry_or_create(pml4, page, frame_allocator)?;
+        if !entry.is_unused() && !swap::is_swap_entry(entry) {
+            return Err(VmmError::AlreadyMapped(page));
+        }
+
+        let frame: PhysFrame = frame_allocator
+            .allocate_frame()
+            .ok_or(VmmError::OutOfMemory)?;
+        Ok((entry, frame))
+    })?;
+
+    if swap::is_swap_entry(entry) {
+        // the regions lock keeps the entry as it is while the device reads
+        let result = unsafe { swap::read_in(entry, frame, page, flags) };
+        if result.is_err() {
+            with_frame_allocator(|frame_allocator| unsafe {
+                frame_allocator.deallocate_frame(frame)
+            });
+        }
+        return result;
+    }
+
+    unsafe {
+        super::phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, Size4KiB::SIZE as usize);
+    }
+    entry.set_frame(frame, flags);
+
+    Ok(())
+}
+
+/// Runs `f` with the frame allocator, for code that holds the regions lock of an address space
+/// but not the allocator
+fn with_frame_allocator<R>(f: impl FnOnce(&mut KernelFrameAllocator) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        f(&mut FRAME_ALLOCATOR
+            .get()
+            .expect("frame allocator not initialized")
+            .lock())
+    })
+}
+
+/// Releases the swap slots of the swapped out pages in `len` bytes at `virt`
+fn free_swap_entries(pml4: &mut PageTable, virt: VirtAddr, len: u64) {
+    for addr in (virt.as_u64()..virt.as_u64() + len).step_by(Size4KiB::SIZE as usize) {
+        if let Some(entry) = leaf_slot(pml4, VirtAddr::new(addr))
+            && swap::is_swap_entry(entry)
+        {
+            swap::free_entry(entry);
+        }
+    }
+}
+
+/// Swaps out up to `count` user pages that weren't accessed since the last scan, for
+/// [`swap::reclaim`]. Address spaces whose tables are in use right now are skipped, and every
+/// address space gives up at most [`swap::BATCH_SIZE`] pages per pass.
+pub(super) fn swap_out_cold_pages(count: usize) -> usize {
+    interrupts::without_interrupts(|| {
+        let Some(spaces) = SPACES.try_lock() else {
+            return 0;
+        };
+        let active_root = Cr3::read().0;
+        let mut swapped = 0;
+
+        // the first pass only takes away the accessed bits of recently used pages, the second one
+        // swaps those out too if there weren't enough cold pages
+        for _ in 0..2 {
+            for space in spaces.iter() {
+                let Some(_regions) = space.regions.try_lock() else {
+                    continue;
+                };
+
+                let mut batch = swap::SwapBatch::new((count - swapped).min(swap::BATCH_SIZE));
+                {
+                    let Some(frame_allocator) = FRAME_ALLOCATOR
+                        .get()
+                        .expect("frame allocator not initialized")
+                        .try_lock()
+                    else {
+                        return swapped;
+                    };
+
+                    unsafe {
+                        swap_out_table(
+                            table_at(space.pml4.start_address()),
+                            4,
+                            0,
+                            space.root == active_root,
+                            &mut batch,
+                            &frame_allocator,
+                        )
+                    };
+                }
+
+                // the pages are written without the frame allocator, the regions lock keeps the
+                // entries in flight until they are finished
+                swapped += unsafe { batch.write_out() };
+                if swapped == count {
+                    return swapped;
+                }
+            }
+        }
+
+        swapped
+    })
+}
+
+/// Adds pages below `table` whose accessed bit is clear to `batch` until it is full and clears
+/// the bit of the others
+///
+/// # Safety
+///
+/// `table` must be a table of the given `level` in a lower half hierarchy that covers `base`,
+/// `active` whether that hierarchy is loaded. The caller must hold the regions lock until the
+/// batch has been written out.
+unsafe fn swap_out_table(
+    table: &mut PageTable,
+    level: u8,
+    base: u64,
+    active: bool,
+    batch: &mut swap::SwapBatch,
+    frame_allocator: &KernelFrameAllocator,
+) {
+    let entries = if level == 4 {
+        0..KERNEL_ENTRIES.start
+    } else {
+        0..512
+    };
+
+    for i in entries {
+        if batch.is_full() {
+            break;
+        }
+
+        let entry = &mut table[i];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            continue;
+        }
+
+        // 9 bits of the address are translated per level
+        let addr = VirtAddr::new(base | (i as u64) << (12 + (level as u64 - 1) * 9));
+
+        if level > 1 {
+            unsafe {
+                swap_out_table(
+                    table_at(entry.addr()),
+                    level - 1,
+                    addr.as_u64(),
+                    active,
+                    batch,
+                    frame_allocator,
+                )
+            };
+            continue;
+        }
+
+        if flags.contains(PageTableFlags::ACCESSED) {
+            entry.set_flags(flags - PageTableFlags::ACCESSED);
+            if active {
+                tlb::flush(addr);
+            }
+            continue;
+        }
+
+        // a frame shared after a fork is still mapped by the other address space
+        if frame_allocator.ref_count(PhysFrame::containing_address(entry.addr())) != 1 {
+            continue;
+        }
+
+        if !unsafe { batch.add(entry) } {
+            break;
+        }
+        if active {
+            tlb::flush(addr);
+        }
+    }
+}
+
+/// Drops the references to every frame below a lower half table and frees the tables themselves
+unsafe fn free_user_table(table: PhysAddr, level: u8, frame_allocator: &mut KernelFrameAllocator) {
+    for entry in unsafe { table_at(table) }.iter_mut() {
+        let flags = entry.flags();
+        if swap::is_swap_entry(entry) {
+            swap::free_entry(entry);
+            continue;
+        }
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            // 9 bits of the address are translated per level
+            let order = (level as usize - 1) * 9;
+            let frame = PhysFrame::containing_address(entry.addr());
+            unsafe { frame_allocator.deallocate_contiguous(frame, order) };
+        } else {
+            unsafe { free_user_table(entry.addr(), level - 1, frame_allocator) };
+        }
+    }
+
+    unsafe { frame_allocator.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(table)) };
+}
+
+// tables come straight from the frame allocator, a page-aligned slab object would need a whole
+// frame for the slab header in front of it
+fn allocate_table(frame_allocator: &mut KernelFrameAllocator) -> Option<PhysFrame> {
+    let frame = frame_allocator.allocate_frame()?;
+    unsafe { table_at(frame.start_address()) }.zero();
+    Some(frame)
+}
+
+/// # Safety
+///
+/// `addr` must point to a page table that nothing else accesses while the reference lives
+unsafe fn table_at<'a>(addr: PhysAddr) -> &'a mut PageTable {
+    unsafe { &mut *super::phys_to_virt(addr).as_mut_ptr() }
+}
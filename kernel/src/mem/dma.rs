@@ -0,0 +1,189 @@
+//! Physically contiguous buffers for device DMA.
+//!
+//! Buffers are accessed through the hhdm, which maps memory write-back. That is coherent on x86
+//! since devices snoop the caches, so no flushing is needed around transfers.
+
+use core::{
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::interrupts,
+    structures::paging::{PageSize, PhysFrame, Size4KiB},
+};
+
+use super::{FRAME_ALLOCATOR, frame_allocator::MAX_ORDER};
+
+/// Where in physical memory a buffer has to be for the device to reach it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaConstraints {
+    /// Alignment of the physical address, a power of two
+    pub align: u64,
+    /// The buffer has to end at or below this address
+    pub limit: PhysAddr,
+}
+
+impl DmaConstraints {
+    pub const ANY: Self = Self {
+        align: Size4KiB::SIZE,
+        limit: PhysAddr::new_truncate(u64::MAX),
+    };
+
+    /// For devices limited to 32-bit addresses
+    pub const BELOW_4GIB: Self = Self {
+        align: Size4KiB::SIZE,
+        limit: PhysAddr::new_truncate(1 << 32),
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// The alignment is not a power of two
+    InvalidAlignment,
+    /// The buffer is larger than the biggest block the frame allocator hands out
+    TooLarge,
+    /// No free block satisfies the constraints
+    OutOfMemory,
+}
+
+/// Owns physically contiguous frames holding a `T`, freed on drop
+pub struct DmaBuffer<T: ?Sized> {
+    ptr: NonNull<T>,
+    frame: PhysFrame,
+    order: usize,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for DmaBuffer<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for DmaBuffer<T> {}
+
+impl<T> DmaBuffer<T> {
+    pub fn new(value: T, constraints: DmaConstraints) -> Result<Self, DmaError> {
+        let constraints = DmaConstraints {
+            align: constraints.align.max(align_of::<T>() as u64),
+            ..constraints
+        };
+        let (frame, order) = allocate(size_of::<T>() as u64, constraints)?;
+
+        let ptr = super::phys_to_virt(frame.start_address()).as_mut_ptr::<T>();
+        unsafe { ptr.write(value) };
+
+        Ok(Self {
+            ptr: NonNull::new(ptr).expect("hhdm maps a frame at null"),
+            frame,
+            order,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T: Copy> DmaBuffer<[T]> {
+    /// Allocates a buffer of `len` elements, all set to `value`
+    pub fn new_slice(len: usize, value: T, constraints: DmaConstraints) -> Result<Self, DmaError> {
+        let constraints = DmaConstraints {
+            align: constraints.align.max(align_of::<T>() as u64),
+            ..constraints
+        };
+        let size = size_of::<T>().checked_mul(len).ok_or(DmaError::TooLarge)?;
+        let (frame, order) = allocate(size as u64, constraints)?;
+
+        let data = super::phys_to_virt(frame.start_address()).as_mut_ptr::<T>();
+        for i in 0..len {
+            unsafe { data.add(i).write(value) };
+        }
+
+        Ok(Self {
+            ptr: NonNull::new(ptr::slice_from_raw_parts_mut(data, len))
+                .expect("hhdm maps a frame at null"),
+            frame,
+            order,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T: ?Sized> DmaBuffer<T> {
+    /// Address to program into the device
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.frame.start_address()
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.ptr.as_ptr() as *const u8)
+    }
+
+    /// Size of the underlying allocation, which may be larger than the `T`
+    pub fn allocated_size(&self) -> u64 {
+        Size4KiB::SIZE << self.order
+    }
+}
+
+impl<T: ?Sized> Deref for DmaBuffer<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for DmaBuffer<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: ?Sized> Drop for DmaBuffer<T> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.ptr.as_ptr()) };
+
+        interrupts::without_interrupts(|| unsafe {
+            FRAME_ALLOCATOR
+                .get()
+                .expect("frame allocator not initialized")
+                .lock()
+                .deallocate_contiguous(self.frame, self.order)
+        })
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for DmaBuffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DmaBuffer")
+            .field("phys_addr", &self.phys_addr())
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+/// Allocates the smallest block that holds `size` bytes and, as blocks are naturally aligned,
+/// satisfies the alignment
+fn allocate(size: u64, constraints: DmaConstraints) -> Result<(PhysFrame, usize), DmaError> {
+    if !constraints.align.is_power_of_two() {
+        return Err(DmaError::InvalidAlignment);
+    }
+
+    let frames = size
+        .max(constraints.align)
+        .div_ceil(Size4KiB::SIZE)
+        .next_power_of_two();
+    let order = frames.trailing_zeros() as usize;
+
+    if order > MAX_ORDER {
+        return Err(DmaError::TooLarge);
+    }
+
+    let frame = interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR
+            .get()
+            .expect("frame allocator not initialized")
+            .lock()
+            .allocate_contiguous_below(order, constraints.limit)
+    })
+    .ok_or(DmaError::OutOfMemory)?;
+
+    Ok((frame, order))
+}
@@ -0,0 +1,152 @@
+//! Page fault resolution.
+//!
+//! Faults inside a region with [`Backing::Demand`] are resolved by mapping a zeroed frame, or by
+//! reading the page back from swap if it was swapped out, and writes to copy-on-write pages by
+//! unsharing the page. Every other fault is an invalid access and reported back to the interrupt
+//! handler. Lower half addresses are looked up in the active
+//! [`address_space::AddressSpace`], upper half ones in the kernel regions.
+
+use x86_64::{
+    VirtAddr,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{PageSize, PageTableFlags, Size4KiB},
+    },
+};
+
+use super::{
+    FRAME_ALLOCATOR, MAPPER, address_space, heap, slab, swap,
+    vma::{self, Backing, Region, RegionKind},
+    vmm::{self, VmmError},
+};
+
+/// Pages swapped out at once when a fault runs out of memory
+const SWAP_OUT_BATCH: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+pub enum PageFaultError {
+    /// The address isn't part of any region
+    NoRegion,
+    /// The address is in the heap's reservation, past the memory the allocator has been given
+    BeyondHeap(Region),
+    /// The access hit the guard page below a kernel stack
+    StackOverflow(Region),
+    /// The region is mapped by its owner, so the page should have been present
+    NotDemandPaged(Region),
+    /// The page is present but the access isn't allowed by its flags
+    ProtectionViolation,
+    /// The access isn't allowed by the flags of the region
+    AccessDenied(Region),
+    /// The fault happened while the page tables were locked, resolving it would deadlock
+    PageTablesLocked,
+    /// Backing the page failed
+    Vmm(VmmError),
+}
+
+/// Tries to resolve a fault at `addr` by backing the page with a zeroed frame
+pub fn handle_page_fault(addr: VirtAddr, error: PageFaultErrorCode) -> Result<(), PageFaultError> {
+    let page = addr.align_down(Size4KiB::SIZE);
+
+    if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return copy_on_write(page, error);
+    }
+
+    if address_space::is_user_address(addr) {
+        let space = address_space::active().ok_or(PageFaultError::NoRegion)?;
+        let region = space.find_region(addr).ok_or(PageFaultError::NoRegion)?;
+        let flags = demand_flags(region, error)?;
+
+        if space.is_locked() || FRAME_ALLOCATOR.get().is_some_and(|f| f.is_locked()) {
+            return Err(PageFaultError::PageTablesLocked);
+        }
+
+        return commit(|| space.commit_page(page, flags), true);
+    }
+
+    let Some(region) = vma::find(addr) else {
+        return Err(match vma::find_by_guard(addr) {
+            Some(stack) if stack.kind == RegionKind::KernelStack => {
+                PageFaultError::StackOverflow(stack)
+            }
+            _ => PageFaultError::NoRegion,
+        });
+    };
+    if region.kind == RegionKind::Heap && !heap::contains(addr) {
+        return Err(PageFaultError::BeyondHeap(region));
+    }
+    let flags = demand_flags(region, error)?;
+
+    if MAPPER.get().is_some_and(|m| m.is_locked())
+        || FRAME_ALLOCATOR.get().is_some_and(|f| f.is_locked())
+    {
+        return Err(PageFaultError::PageTablesLocked);
+    }
+
+    // kernel faults may come from inside the heap, swap drivers are allowed to allocate
+    commit(|| vmm::allocate_range(page, Size4KiB::SIZE, flags), false)
+}
+
+/// Resolves a write to a present page, which is only allowed if the page is copy-on-write
+fn copy_on_write(page: VirtAddr, error: PageFaultErrorCode) -> Result<(), PageFaultError> {
+    if !error.contains(PageFaultErrorCode::CAUSED_BY_WRITE) || !address_space::is_user_address(page)
+    {
+        return Err(PageFaultError::ProtectionViolation);
+    }
+
+    let space = address_space::active().ok_or(PageFaultError::ProtectionViolation)?;
+
+    if space.is_locked() || FRAME_ALLOCATOR.get().is_some_and(|f| f.is_locked()) {
+        return Err(PageFaultError::PageTablesLocked);
+    }
+
+    if !space.is_copy_on_write(page) {
+        return Err(PageFaultError::ProtectionViolation);
+    }
+
+    commit(|| space.unshare_page(page), true)
+}
+
+/// Flags to back a page of `region` with, as long as the faulting access is allowed in it
+fn demand_flags(
+    region: Region,
+    error: PageFaultErrorCode,
+) -> Result<PageTableFlags, PageFaultError> {
+    let Backing::Demand(flags) = region.backing else {
+        return Err(PageFaultError::NotDemandPaged(region));
+    };
+
+    if (error.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !flags.contains(PageTableFlags::WRITABLE))
+        || (error.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            && flags.contains(PageTableFlags::NO_EXECUTE))
+        || (error.contains(PageFaultErrorCode::USER_MODE)
+            && !flags.contains(PageTableFlags::USER_ACCESSIBLE))
+    {
+        return Err(PageFaultError::AccessDenied(region));
+    }
+
+    Ok(flags)
+}
+
+/// Maps the page through `map`, retrying if memory ran out. Cold user pages are only written to
+/// swap if `may_swap`, the swap device may need the heap.
+fn commit(
+    mut map: impl FnMut() -> Result<(), VmmError>,
+    may_swap: bool,
+) -> Result<(), PageFaultError> {
+    let mut result = map();
+
+    // cached slabs are the cheapest memory to give back, cold user pages can go to swap
+    if result == Err(VmmError::OutOfMemory) && slab::shrink_all() > 0 {
+        result = map();
+    }
+    if result == Err(VmmError::OutOfMemory) && may_swap && swap::reclaim(SWAP_OUT_BATCH) > 0 {
+        result = map();
+    }
+
+    match result {
+        // another fault on the same page got there first
+        Ok(()) | Err(VmmError::AlreadyMapped(_)) => Ok(()),
+        Err(err) => Err(PageFaultError::Vmm(err)),
+    }
+}
@@ -5,33 +5,102 @@ use limine::{
 use spin::{Mutex, Once};
 use x86_64::{
     PhysAddr,
-    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
 };
 
+use super::numa::NumaTopology;
+
 #[used]
 #[unsafe(link_section = ".requests")]
 static MEMORY_MAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
 
 pub static FRAME_ALLOCATOR: Once<Mutex<KernelFrameAllocator>> = Once::new();
 
+/// Largest block the allocator hands out, 2^18 frames (1 GiB)
+pub const MAX_ORDER: usize = 18;
+/// NUMA nodes the allocator keeps separate pools for
+pub const MAX_NODES: usize = 8;
+/// Memory ranges a NUMA topology may assign to nodes
+const MAX_NODE_RANGES: usize = 32;
+
+const FRAME_SIZE: u64 = 4096;
+const NO_FRAME: u32 = u32::MAX;
+
 /// # Safety
 ///
 /// The caller must ensure that all the Usable regions in memory_map is unused
 pub unsafe fn init_frame_allocator() {
-    FRAME_ALLOCATOR.call_once(|| unsafe {
-        let memory_map = MEMORY_MAP_REQUEST
-            .get_response()
-            .expect("missing memory map")
-            .entries();
-        Mutex::new(KernelFrameAllocator::new(memory_map))
-    });
+    FRAME_ALLOCATOR.call_once(|| unsafe { Mutex::new(KernelFrameAllocator::new(memory_map())) });
+}
+
+/// The memory map lives in bootloader reclaimable memory, so it must not be used once that memory
+/// has been reclaimed
+pub(super) fn memory_map() -> &'static [&'static Entry] {
+    MEMORY_MAP_REQUEST
+        .get_response()
+        .expect("missing memory map")
+        .entries()
+}
+
+/// Whether the region holds RAM that ends up in the free lists at some point
+fn is_reclaimable_ram(entry_type: EntryType) -> bool {
+    entry_type == EntryType::USABLE
+        || entry_type == EntryType::BOOTLOADER_RECLAIMABLE
+        || entry_type == EntryType::ACPI_RECLAIMABLE
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum FrameState {
+    /// Not backed by usable memory, never handed out
+    Reserved,
+    /// First frame of a block sitting in a free list
+    Free,
+    /// First frame of a block owned by someone, or part of a larger block
+    Allocated,
+}
+
+/// Per-frame bookkeeping, the free lists are threaded through these entries
+#[derive(Clone, Copy)]
+struct FrameInfo {
+    next: u32,
+    prev: u32,
+    order: u8,
+    state: FrameState,
+    /// Number of owners of an allocated block, kept in its first frame
+    ref_count: u16,
+}
+
+/// Frames `start..end` belong to `node`
+#[derive(Clone, Copy)]
+struct NodeRange {
+    start: u32,
+    end: u32,
+    node: u8,
 }
 
+/// Buddy allocator over all physical frames below the highest usable address.
+///
+/// Every NUMA node has free lists of its own and free blocks never span two nodes. Until
+/// [`Self::set_topology`] is called all memory belongs to node 0, so blocks allocated before
+/// that may span two nodes and are split up again when freed.
 pub struct KernelFrameAllocator {
-    bitmap: &'static mut [u64],
-    max_frames: u64,
-    // optimization so that we do not search over already allocated frames
-    next_free_frame: u64,
+    frames: &'static mut [FrameInfo],
+    free_lists: [[u32; MAX_ORDER + 1]; MAX_NODES],
+    /// Frames outside of every range belong to node 0
+    node_ranges: [NodeRange; MAX_NODE_RANGES],
+    node_range_count: usize,
+    node_count: usize,
+    /// Nodes to allocate from when preferring a node, closest first
+    fallback: [[u8; MAX_NODES]; MAX_NODES],
+    /// Node allocations prefer unless told otherwise
+    local_node: usize,
+    /// Frames ever handed to the free lists, including reclaimed ones
+    managed_frames: u64,
+    free_frames: u64,
+    node_free_frames: [u64; MAX_NODES],
 }
 
 impl KernelFrameAllocator {
@@ -39,89 +108,446 @@ impl KernelFrameAllocator {
     ///
     /// The caller must ensure that all the Usable regions in memory_map is unused
     pub unsafe fn new(memory_map: &[&Entry]) -> Self {
-        let last_region = memory_map.iter().last().expect("no memory region");
-        let total_frames = (last_region.base + last_region.length) / 4096;
-        let bitmap_size = total_frames.div_ceil(8);
+        // reclaimable regions are covered too so that they can be handed over after boot
+        let max_frames = memory_map
+            .iter()
+            .filter(|r| is_reclaimable_ram(r.entry_type))
+            .map(|r| (r.base + r.length) / FRAME_SIZE)
+            .max()
+            .expect("no usable memory region");
+
+        let metadata_size =
+            (max_frames * size_of::<FrameInfo>() as u64).next_multiple_of(FRAME_SIZE);
 
-        let bitmap_memory_region = memory_map
+        let metadata_region = memory_map
             .iter()
-            .find(|r| r.entry_type == EntryType::USABLE && r.length >= bitmap_size)
-            .expect("could not find a memory region large enough for the bitmap");
+            .find(|r| r.entry_type == EntryType::USABLE && r.length >= metadata_size)
+            .expect("could not find a memory region large enough for the frame metadata");
 
-        let bitmap_start_addr = bitmap_memory_region.base;
+        let metadata_start = metadata_region.base;
+        let metadata_end = metadata_start + metadata_size;
 
-        let bitmap_slice = unsafe {
+        let frames = unsafe {
             core::slice::from_raw_parts_mut(
-                super::phys_to_virt(PhysAddr::new(bitmap_start_addr)).as_mut_ptr(),
-                bitmap_size as usize / 8,
+                super::phys_to_virt(PhysAddr::new(metadata_start)).as_mut_ptr::<FrameInfo>(),
+                max_frames as usize,
             )
         };
 
-        // start from safe state (everything is used)
-        bitmap_slice.fill(0xFF);
+        // start from safe state (everything is reserved)
+        frames.fill(FrameInfo {
+            next: NO_FRAME,
+            prev: NO_FRAME,
+            order: 0,
+            state: FrameState::Reserved,
+            ref_count: 0,
+        });
 
         let mut allocator = Self {
-            bitmap: bitmap_slice,
-            max_frames: total_frames,
-            next_free_frame: 0,
+            frames,
+            free_lists: [[NO_FRAME; MAX_ORDER + 1]; MAX_NODES],
+            node_ranges: [NodeRange {
+                start: 0,
+                end: 0,
+                node: 0,
+            }; MAX_NODE_RANGES],
+            node_range_count: 0,
+            node_count: 1,
+            fallback: [[0; MAX_NODES]; MAX_NODES],
+            local_node: 0,
+            managed_frames: 0,
+            free_frames: 0,
+            node_free_frames: [0; MAX_NODES],
         };
 
-        memory_map
+        for region in memory_map
             .iter()
             .filter(|r| r.entry_type == EntryType::USABLE)
-            .for_each(|r| allocator.mark_range_as_free(r.base, r.base + r.length));
+        {
+            // skip the frames holding the metadata itself
+            let start = if region.base == metadata_start {
+                metadata_end
+            } else {
+                region.base
+            };
 
-        // mark the bitmap memory as used
-        allocator.mark_range_as_used(bitmap_start_addr, bitmap_start_addr + bitmap_size);
-        allocator.next_free_frame = (bitmap_start_addr + bitmap_size) / 4096;
+            unsafe { allocator.free_range(start, region.base + region.length) };
+        }
 
         allocator
     }
 
-    fn mark_range_as_free(&mut self, start: u64, end: u64) {
-        for frame_addr in (start..end).step_by(4096) {
-            let frame_idx = (frame_addr / 4096) as usize;
-            self.bitmap[frame_idx / 64] &= !(1 << (frame_idx % 64));
+    /// Allocates `2^order` physically contiguous frames aligned to their size
+    pub fn allocate_contiguous(&mut self, order: usize) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate_block(order, u64::MAX, self.local_node)
+            .map(|idx| frame_at(idx as u64 * FRAME_SIZE))
+    }
+
+    /// Like [`Self::allocate_contiguous`], but takes the block from `node` if it has one and from
+    /// the closest other node otherwise
+    pub fn allocate_contiguous_on(
+        &mut self,
+        order: usize,
+        node: usize,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        assert!(node < self.node_count, "no numa node {node}");
+
+        self.allocate_block(order, u64::MAX, node)
+            .map(|idx| frame_at(idx as u64 * FRAME_SIZE))
+    }
+
+    /// Like [`Self::allocate_contiguous`], but the whole block ends at or below `limit`
+    pub fn allocate_contiguous_below(
+        &mut self,
+        order: usize,
+        limit: PhysAddr,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate_block(order, limit.as_u64() / FRAME_SIZE, self.local_node)
+            .map(|idx| frame_at(idx as u64 * FRAME_SIZE))
+    }
+
+    /// Drops a reference to a block, which is freed once the last reference is gone
+    ///
+    /// # Safety
+    ///
+    /// `frame` must be the start of a block returned by [`Self::allocate_contiguous`] with the same
+    /// `order`, and the caller must not use the block afterwards
+    pub unsafe fn deallocate_contiguous(&mut self, frame: PhysFrame<Size4KiB>, order: usize) {
+        let idx = frame_index(frame);
+        let info = &mut self.frames[idx as usize];
+        debug_assert!(
+            info.state == FrameState::Allocated && info.ref_count > 0,
+            "freeing a block that is not allocated"
+        );
+
+        info.ref_count -= 1;
+        if info.ref_count == 0 {
+            self.free_frame_range(idx as u64, idx as u64 + (1 << order));
         }
     }
 
-    fn mark_range_as_used(&mut self, start: u64, end: u64) {
-        for frame_addr in (start..end).step_by(4096) {
-            let frame_idx = (frame_addr / 4096) as usize;
-            self.bitmap[frame_idx / 64] |= 1 << (frame_idx % 64);
+    /// Adds a reference to an allocated block, so that it stays allocated until every owner has
+    /// deallocated it
+    pub fn share(&mut self, frame: PhysFrame<Size4KiB>) {
+        let info = &mut self.frames[frame_index(frame) as usize];
+        assert!(
+            info.state == FrameState::Allocated && info.ref_count > 0,
+            "sharing a block that is not allocated"
+        );
+
+        info.ref_count = info
+            .ref_count
+            .checked_add(1)
+            .expect("frame reference count overflow");
+    }
+
+    /// Number of owners of the block starting at `frame`, 0 if it isn't allocated
+    pub fn ref_count(&self, frame: PhysFrame<Size4KiB>) -> u16 {
+        self.frames
+            .get(frame_index(frame) as usize)
+            .map_or(0, |info| info.ref_count)
+    }
+
+    /// Hands every whole frame in `start..end` to the free lists
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that nothing uses the memory in the range anymore and that none of
+    /// it is already free
+    pub unsafe fn free_range(&mut self, start: u64, end: u64) {
+        let start_idx = start.div_ceil(FRAME_SIZE);
+        let end_idx = (end / FRAME_SIZE).min(self.frames.len() as u64);
+
+        self.free_frame_range(start_idx, end_idx);
+        self.managed_frames += end_idx.saturating_sub(start_idx);
+    }
+
+    /// Assigns memory to the nodes of `topology` and moves every free block to the pool of its
+    /// node, splitting blocks that span two nodes
+    pub fn set_topology(&mut self, topology: &NumaTopology, local_node: usize) {
+        let node_count = topology.node_count();
+        assert!(
+            (1..=MAX_NODES).contains(&node_count),
+            "unsupported number of numa nodes"
+        );
+
+        self.node_range_count = 0;
+        for range in topology.memory() {
+            let start = range.base.as_u64().div_ceil(FRAME_SIZE);
+            let end =
+                ((range.base.as_u64() + range.length) / FRAME_SIZE).min(self.frames.len() as u64);
+            if start >= end {
+                continue;
+            }
+
+            assert!(
+                self.node_range_count < MAX_NODE_RANGES,
+                "too many numa memory ranges"
+            );
+            self.node_ranges[self.node_range_count] = NodeRange {
+                start: start as u32,
+                end: end as u32,
+                node: range.node as u8,
+            };
+            self.node_range_count += 1;
+        }
+
+        for from in 0..node_count {
+            let fallback = &mut self.fallback[from][..node_count];
+            for (to, node) in fallback.iter_mut().enumerate() {
+                *node = to as u8;
+            }
+            fallback.sort_unstable_by_key(|&to| (topology.distance(from, to as usize), to));
+        }
+
+        self.node_count = node_count;
+        self.local_node = local_node;
+
+        // detach every free block first, so that none of them gets merged before it is moved
+        let free_lists =
+            core::mem::replace(&mut self.free_lists, [[NO_FRAME; MAX_ORDER + 1]; MAX_NODES]);
+        for &head in free_lists.iter().flatten() {
+            let mut idx = head;
+            while idx != NO_FRAME {
+                self.frames[idx as usize].state = FrameState::Allocated;
+                idx = self.frames[idx as usize].next;
+            }
+        }
+
+        self.free_frames = 0;
+        self.node_free_frames = [0; MAX_NODES];
+
+        for (order, &head) in free_lists.iter().flat_map(|lists| lists.iter().enumerate()) {
+            let mut idx = head;
+            while idx != NO_FRAME {
+                let next = self.frames[idx as usize].next;
+                self.free_frame_range(idx as u64, idx as u64 + (1 << order));
+                idx = next;
+            }
         }
     }
-}
 
-unsafe impl FrameAllocator<Size4KiB> for KernelFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        for frame_idx in self.next_free_frame..self.max_frames {
-            let byte_idx = frame_idx / 64;
-            let bit_idx = frame_idx % 64;
+    pub fn node_count(&self) -> usize {
+        self.node_count
+    }
+
+    /// Node the frame belongs to
+    pub fn node_of(&self, frame: PhysFrame<Size4KiB>) -> usize {
+        self.node_run(frame_index(frame)).0
+    }
+
+    /// Number of frames the allocator owns, free or not
+    pub fn managed_frames(&self) -> u64 {
+        self.managed_frames
+    }
+
+    pub fn free_frames(&self) -> u64 {
+        self.free_frames
+    }
+
+    pub fn node_free_frames(&self, node: usize) -> u64 {
+        self.node_free_frames[node]
+    }
+
+    /// Frames taken up by the per-frame bookkeeping
+    pub fn metadata_frames(&self) -> u64 {
+        size_of_val(self.frames).div_ceil(FRAME_SIZE as usize) as u64
+    }
+
+    /// Allocates a block whose first `2^order` frames end at or below frame index `end_limit`,
+    /// from `node` or the closest node that has one
+    fn allocate_block(&mut self, order: usize, end_limit: u64, node: usize) -> Option<u32> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        // splitting keeps the lowest part of a block, so only the start of a block matters
+        let fits = |idx: u32| idx as u64 + (1 << order) <= end_limit;
+        let (node, idx, mut current_order) = self.fallback[node][..self.node_count]
+            .iter()
+            .find_map(|&node| {
+                let node = node as usize;
+                (order..=MAX_ORDER).find_map(|o| {
+                    let mut idx = self.free_lists[node][o];
+                    while idx != NO_FRAME && !fits(idx) {
+                        idx = self.frames[idx as usize].next;
+                    }
+                    (idx != NO_FRAME).then_some((node, idx, o))
+                })
+            })?;
+        self.remove_from_list(idx, current_order, node);
+
+        // split the block, giving the upper halves back until it has the requested size
+        while current_order > order {
+            current_order -= 1;
+            self.push_to_list(idx + (1 << current_order), current_order, node);
+        }
+
+        let frame = &mut self.frames[idx as usize];
+        frame.state = FrameState::Allocated;
+        frame.order = order as u8;
+        frame.ref_count = 1;
+        self.free_frames -= 1 << order;
+        self.node_free_frames[node] -= 1 << order;
+
+        Some(idx)
+    }
+
+    /// Frees the frames `idx..end_idx` in the biggest blocks that neither leave the range nor
+    /// cross into another node
+    fn free_frame_range(&mut self, mut idx: u64, end_idx: u64) {
+        while idx < end_idx {
+            let run_end = (self.node_run(idx as u32).1 as u64).min(end_idx);
+
+            // biggest naturally aligned block that starts at idx and fits in the run
+            let mut order = (idx.trailing_zeros() as usize).min(MAX_ORDER);
+            while idx + (1 << order) > run_end {
+                order -= 1;
+            }
+
+            self.free_block(idx as u32, order);
+            idx += 1 << order;
+        }
+    }
 
-            if (self.bitmap[byte_idx as usize] & (1 << bit_idx)) == 0 {
-                self.bitmap[byte_idx as usize] |= 1 << bit_idx;
-                self.next_free_frame += 1;
+    /// Frees a block that lies within one node, see [`Self::free_frame_range`] for the others
+    fn free_block(&mut self, mut idx: u32, mut order: usize) {
+        let node = self.node_run(idx).0;
+        self.free_frames += 1 << order;
+        self.node_free_frames[node] += 1 << order;
 
-                let addr = frame_idx * 4096;
-                return Some(PhysFrame::containing_address(PhysAddr::new(addr)));
+        // merge with the buddy as long as it is a free block of the same size and node
+        while order < MAX_ORDER {
+            let buddy = idx ^ (1 << order);
+
+            match self.frames.get(buddy as usize) {
+                Some(info)
+                    if info.state == FrameState::Free
+                        && info.order as usize == order
+                        && self.node_run(buddy).0 == node => {}
+                _ => break,
             }
+
+            self.remove_from_list(buddy, order, node);
+            self.frames[buddy as usize].state = FrameState::Allocated;
+
+            idx = idx.min(buddy);
+            order += 1;
+        }
+
+        self.push_to_list(idx, order, node);
+    }
+
+    /// Node of frame `idx` and the first frame after it that may belong to another node
+    fn node_run(&self, idx: u32) -> (usize, u32) {
+        let ranges = &self.node_ranges[..self.node_range_count];
+
+        if let Some(range) = ranges.iter().find(|r| (r.start..r.end).contains(&idx)) {
+            return (range.node as usize, range.end);
+        }
+
+        let next_start = ranges
+            .iter()
+            .map(|r| r.start)
+            .filter(|&start| start > idx)
+            .min()
+            .unwrap_or(u32::MAX);
+        (0, next_start)
+    }
+
+    fn push_to_list(&mut self, idx: u32, order: usize, node: usize) {
+        let head = self.free_lists[node][order];
+
+        self.frames[idx as usize] = FrameInfo {
+            next: head,
+            prev: NO_FRAME,
+            order: order as u8,
+            state: FrameState::Free,
+            ref_count: 0,
+        };
+
+        if head != NO_FRAME {
+            self.frames[head as usize].prev = idx;
+        }
+        self.free_lists[node][order] = idx;
+    }
+
+    fn remove_from_list(&mut self, idx: u32, order: usize, node: usize) {
+        let FrameInfo { next, prev, .. } = self.frames[idx as usize];
+
+        if prev == NO_FRAME {
+            self.free_lists[node][order] = next;
+        } else {
+            self.frames[prev as usize].next = next;
+        }
+
+        if next != NO_FRAME {
+            self.frames[next as usize].prev = prev;
         }
 
-        None // no free frames
+        let frame = &mut self.frames[idx as usize];
+        frame.next = NO_FRAME;
+        frame.prev = NO_FRAME;
+    }
+}
+
+fn frame_at(addr: u64) -> PhysFrame<Size4KiB> {
+    PhysFrame::containing_address(PhysAddr::new(addr))
+}
+
+fn frame_index(frame: PhysFrame<Size4KiB>) -> u32 {
+    (frame.start_address().as_u64() / FRAME_SIZE) as u32
+}
+
+/// Buddy order of a block the size of one `S` frame
+pub const fn order_of<S: PageSize>() -> usize {
+    (S::SIZE / FRAME_SIZE).trailing_zeros() as usize
+}
+
+unsafe impl FrameAllocator<Size4KiB> for KernelFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate_contiguous(0)
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for KernelFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate_contiguous(order_of::<Size2MiB>())
+            .map(|frame| PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for KernelFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        self.allocate_contiguous(order_of::<Size1GiB>())
+            .map(|frame| PhysFrame::containing_address(frame.start_address()))
     }
 }
 
 impl FrameDeallocator<Size4KiB> for KernelFrameAllocator {
     unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-        let frame_idx = frame.start_address().as_u64() / 4096;
-        let byte_idx = frame_idx / 64;
-        let bit_idx = frame_idx % 64;
+        unsafe { self.deallocate_contiguous(frame, 0) }
+    }
+}
 
-        self.bitmap[byte_idx as usize] &= !(1 << bit_idx);
+impl FrameDeallocator<Size2MiB> for KernelFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        unsafe {
+            self.deallocate_contiguous(
+                frame_at(frame.start_address().as_u64()),
+                order_of::<Size2MiB>(),
+            )
+        }
+    }
+}
 
-        if frame_idx < self.next_free_frame {
-            self.next_free_frame = frame_idx
+impl FrameDeallocator<Size1GiB> for KernelFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        unsafe {
+            self.deallocate_contiguous(
+                frame_at(frame.start_address().as_u64()),
+                order_of::<Size1GiB>(),
+            )
         }
     }
 }
@@ -1,48 +1,265 @@
-use talc::{ErrOnOom, Span, Talc, Talck};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use talc::{OomHandler, Span, Talc, Talck};
 use x86_64::{
     VirtAddr,
+    instructions::interrupts,
     structures::paging::{
-        FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::MapToError,
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
+        mapper::MapToError,
     },
 };
 
-const HEAP_START: usize = 0x_4444_4444_0000;
+#[cfg(feature = "heap-debug")]
+use super::heap_debug::DebugAllocator;
+use super::vma::{self, Backing, RegionKind};
+
+// mapped up front since the heap is used before the page fault handler is installed
 const HEAP_INITIAL_SIZE: usize = 100 * 1024; // 100 KiB
+const HEAP_DEFAULT_LIMIT: usize = 64 * 1024 * 1024; // 64 MiB
+// address space reserved up front, the heap can never grow past this
+const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024 * 1024; // 16 GiB
+// smallest amount the heap grows by, so that small allocations don't map one page at a time
+const HEAP_GROWTH_STEP: usize = 64 * 1024; // 64 KiB
+
+const HEAP_PAGE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
 
 #[global_allocator]
-static ALLOCATOR: Talck<spin::Mutex<()>, ErrOnOom> = Talc::new(ErrOnOom).lock();
+static GLOBAL_ALLOCATOR: KernelAllocator = KernelAllocator;
+
+static ALLOCATOR: Talck<spin::Mutex<()>, HeapGrower> = Talc::new(HeapGrower::new()).lock();
+
+/// What [`KernelAllocator`] forwards to, talc itself unless heap debugging is enabled
+#[cfg(not(feature = "heap-debug"))]
+static BACKEND: &Talck<spin::Mutex<()>, HeapGrower> = &ALLOCATOR;
+#[cfg(feature = "heap-debug")]
+static BACKEND: DebugAllocator<Talck<spin::Mutex<()>, HeapGrower>> =
+    DebugAllocator::new(&ALLOCATOR);
+
+// requested bytes, tracked outside talc so that the peak can be kept without taking its lock
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+// end of the memory handed to talc, read by the page fault handler which can't take talc's lock
+static HEAP_END: AtomicUsize = AtomicUsize::new(0);
+
+/// Forwards to [`BACKEND`] and keeps track of the peak heap usage
+struct KernelAllocator;
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { BACKEND.alloc(layout) };
+        if !ptr.is_null() {
+            record_allocation(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { BACKEND.dealloc(ptr, layout) };
+        ALLOCATED_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { BACKEND.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            ALLOCATED_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+            record_allocation(new_size);
+        }
+        new_ptr
+    }
+}
+
+fn record_allocation(size: usize) {
+    let allocated = ALLOCATED_BYTES.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_ALLOCATED_BYTES.fetch_max(allocated, Ordering::Relaxed);
+}
+
+/// Snapshot of the kernel heap usage
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes the heap currently spans, the pages get backed by frames when first touched
+    pub size_bytes: usize,
+    /// Upper bound the heap is allowed to grow to
+    pub limit_bytes: usize,
+    /// Bytes handed out to live allocations
+    pub allocated_bytes: usize,
+    /// Highest number of bytes requested by live allocations at any point
+    pub peak_allocated_bytes: usize,
+    /// Bytes available for allocation without growing the heap
+    pub free_bytes: usize,
+    /// Number of live allocations
+    pub allocation_count: usize,
+}
+
+/// Grows the heap into the address space right after its current end. The new pages are left to
+/// the page fault handler, so growing never maps anything itself.
+pub struct HeapGrower {
+    start: usize,
+    heap: Span,
+    limit: usize,
+}
+
+impl HeapGrower {
+    const fn new() -> Self {
+        Self {
+            start: 0,
+            heap: Span::empty(),
+            limit: HEAP_DEFAULT_LIMIT,
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.heap
+            .get_base_acme()
+            .map_or(0, |(_, end)| end as usize - self.start)
+    }
+}
+
+impl OomHandler for HeapGrower {
+    fn handle_oom(talc: &mut Talc<Self>, layout: Layout) -> Result<(), ()> {
+        let old_heap = talc.oom_handler.heap;
+        let (_, old_end) = old_heap.get_base_acme().ok_or(())?;
+
+        let remaining = talc
+            .oom_handler
+            .limit
+            .saturating_sub(talc.oom_handler.size());
+
+        // leave room for talc's metadata and the alignment padding of the allocation
+        let wanted = (layout.size() + layout.align())
+            .max(HEAP_GROWTH_STEP)
+            .next_multiple_of(4096)
+            .min(remaining);
+
+        if wanted == 0 {
+            return Err(());
+        }
+
+        let new_heap = Span::new(
+            talc.oom_handler.start as *mut u8,
+            old_end.wrapping_add(wanted),
+        );
+        // talc writes to the new memory while extending, so it must be backed from here on
+        HEAP_END.store(old_end as usize + wanted, Ordering::Relaxed);
+        talc.oom_handler.heap = unsafe { talc.extend(old_heap, new_heap) };
+
+        Ok(())
+    }
+}
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
 ) -> Result<(), MapToError<Size4KiB>> {
-    let heap_page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_INITIAL_SIZE as u64 - 1;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-
-    for page in heap_page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
-        }
+    let heap_start = vma::reserve(
+        HEAP_MAX_SIZE as u64,
+        Size4KiB::SIZE,
+        RegionKind::Heap,
+        Backing::Demand(HEAP_PAGE_FLAGS),
+    )
+    .expect("no address space left for the heap")
+    .as_u64() as usize;
+
+    let mapped = map_heap_range(
+        mapper,
+        frame_allocator,
+        heap_start,
+        heap_start + HEAP_INITIAL_SIZE,
+    );
+
+    if mapped < HEAP_INITIAL_SIZE {
+        return Err(MapToError::FrameAllocationFailed);
     }
 
     let span = Span::new(
-        HEAP_START as *mut u8,
-        (HEAP_START + HEAP_INITIAL_SIZE) as *mut u8,
+        heap_start as *mut u8,
+        (heap_start + HEAP_INITIAL_SIZE) as *mut u8,
     );
 
-    unsafe { ALLOCATOR.lock().claim(span) }
-        .expect("there is too little memory to initialize the heap");
+    HEAP_END.store(heap_start + HEAP_INITIAL_SIZE, Ordering::Relaxed);
+
+    let mut talc = ALLOCATOR.lock();
+    talc.oom_handler.start = heap_start;
+    talc.oom_handler.heap =
+        unsafe { talc.claim(span) }.expect("there is too little memory to initialize the heap");
 
     Ok(())
 }
+
+/// Whether `addr` lies in the part of the heap reservation talc has been given. The rest of the
+/// reservation is only there to grow into, touching it is a bug.
+pub fn contains(addr: VirtAddr) -> bool {
+    (addr.as_u64() as usize) < HEAP_END.load(Ordering::Relaxed)
+}
+
+/// Sets the maximum size the heap may grow to.
+///
+/// The limit can't be lowered below the current size of the heap, nor raised above the address
+/// space reserved for the heap.
+pub fn set_heap_limit(limit: usize) {
+    interrupts::without_interrupts(|| {
+        let mut talc = ALLOCATOR.lock();
+        let size = talc.oom_handler.size();
+        talc.oom_handler.limit = limit.clamp(size, HEAP_MAX_SIZE);
+    })
+}
+
+pub fn heap_stats() -> HeapStats {
+    interrupts::without_interrupts(|| {
+        let talc = ALLOCATOR.lock();
+        let counters = talc.get_counters();
+
+        HeapStats {
+            size_bytes: talc.oom_handler.size(),
+            limit_bytes: talc.oom_handler.limit,
+            allocated_bytes: counters.allocated_bytes,
+            peak_allocated_bytes: PEAK_ALLOCATED_BYTES.load(Ordering::Relaxed),
+            free_bytes: counters.available_bytes,
+            allocation_count: counters.allocation_count,
+        }
+    })
+}
+
+/// Prints every live heap allocation and where it was made to serial
+#[cfg(feature = "heap-debug")]
+pub fn dump_allocations() {
+    BACKEND.dump();
+}
+
+/// Maps fresh frames over `start..end` and returns how many bytes were mapped before running out
+/// of frames
+fn map_heap_range(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    start: usize,
+    end: usize,
+) -> usize {
+    let pages = Page::range(
+        Page::<Size4KiB>::containing_address(VirtAddr::new(start as u64)),
+        Page::containing_address(VirtAddr::new(end as u64)),
+    );
+
+    let mut mapped = 0;
+    for page in pages {
+        let Some(frame) = frame_allocator.allocate_frame() else {
+            break;
+        };
+
+        match unsafe { mapper.map_to(page, frame, HEAP_PAGE_FLAGS, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                break;
+            }
+        }
+
+        mapped += Size4KiB::SIZE as usize;
+    }
+
+    mapped
+}
@@ -0,0 +1,332 @@
+//! Heap debugging, enabled by the `heap-debug` feature.
+//!
+//! [`DebugAllocator`] wraps the real allocator and surrounds every allocation with a header and
+//! two redzones:
+//!
+//! ```text
+//! | header | redzone | data | redzone |
+//! ```
+//!
+//! Redzones are checked on free, freed memory is poisoned so that use after free shows up as
+//! `0xdd` bytes, and the header marks whether the allocation is still live to catch double frees.
+//! Freed allocations sit in a quarantine for a while before the real allocator gets them back, so
+//! that their header survives long enough to catch a double free and writes after the free are
+//! found when the poison is checked on the way out.
+//!
+//! Live allocations are linked into a registry which [`DebugAllocator::dump`] prints to serial
+//! together with the return addresses found on the stack when they were allocated. Those are
+//! found by following frame pointers, the build script refuses the feature without them.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    arch::asm,
+    ptr,
+};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::serial_println;
+
+#[cfg(not(frame_pointers))]
+compile_error!("heap-debug needs frame pointers, build with -C force-frame-pointers=yes");
+
+const REDZONE_SIZE: usize = 16;
+const REDZONE_BYTE: u8 = 0xfd;
+/// Fresh allocations are filled with this, so that reads of uninitialized memory stand out
+const ALLOC_POISON: u8 = 0xaa;
+const FREE_POISON: u8 = 0xdd;
+
+/// Number of freed allocations held back from the real allocator
+const QUARANTINE_LEN: usize = 256;
+
+const LIVE_MAGIC: u64 = 0x11fe_a110_c8ed_11fe;
+const FREED_MAGIC: u64 = 0xdead_f4ee_d0ff_dead;
+
+/// Number of return addresses recorded per allocation, innermost first
+const CALLER_DEPTH: usize = 6;
+/// Stack frames are only followed into the higher half, where all kernel stacks live
+const KERNEL_HALF_START: usize = 0xffff_8000_0000_0000;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+    /// Offset of the data from the header, which depends on the alignment
+    front: usize,
+    next: *mut Header,
+    prev: *mut Header,
+    callers: [usize; CALLER_DEPTH],
+}
+
+/// Intrusive list of the live allocations
+struct Registry {
+    head: *mut Header,
+    allocations: usize,
+    bytes: usize,
+}
+
+// the headers are only reachable through the registry, which is behind a lock
+unsafe impl Send for Registry {}
+
+impl Registry {
+    unsafe fn insert(&mut self, header: *mut Header) {
+        unsafe {
+            (*header).prev = ptr::null_mut();
+            (*header).next = self.head;
+            if !self.head.is_null() {
+                (*self.head).prev = header;
+            }
+            self.allocations += 1;
+            self.bytes += (*header).size;
+        }
+        self.head = header;
+    }
+
+    unsafe fn remove(&mut self, header: *mut Header) {
+        unsafe {
+            let (next, prev) = ((*header).next, (*header).prev);
+            if prev.is_null() {
+                self.head = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+            self.allocations -= 1;
+            self.bytes -= (*header).size;
+        }
+    }
+}
+
+/// Ring of freed allocations with their layouts in the real allocator, oldest first
+struct Quarantine {
+    chunks: [Option<(*mut Header, Layout)>; QUARANTINE_LEN],
+    next: usize,
+}
+
+// like the registry, the chunks are only reachable through the quarantine
+unsafe impl Send for Quarantine {}
+
+impl Quarantine {
+    /// Adds a chunk, returns the oldest one if the quarantine was full
+    fn push(&mut self, header: *mut Header, outer: Layout) -> Option<(*mut Header, Layout)> {
+        let evicted = self.chunks[self.next].replace((header, outer));
+        self.next = (self.next + 1) % QUARANTINE_LEN;
+        evicted
+    }
+}
+
+pub struct DebugAllocator<A: 'static> {
+    inner: &'static A,
+    registry: Mutex<Registry>,
+    quarantine: Mutex<Quarantine>,
+}
+
+impl<A: GlobalAlloc> DebugAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        Self {
+            inner,
+            registry: Mutex::new(Registry {
+                head: ptr::null_mut(),
+                allocations: 0,
+                bytes: 0,
+            }),
+            quarantine: Mutex::new(Quarantine {
+                chunks: [None; QUARANTINE_LEN],
+                next: 0,
+            }),
+        }
+    }
+
+    /// Prints every live allocation to serial, flagging those whose redzones were overwritten
+    pub fn dump(&self) {
+        interrupts::without_interrupts(|| {
+            let registry = self.registry.lock();
+            serial_println!(
+                "{} live heap allocations, {} bytes",
+                registry.allocations,
+                registry.bytes
+            );
+
+            let mut header = registry.head;
+            while !header.is_null() {
+                let (data, size, callers) = unsafe {
+                    (
+                        header.byte_add((*header).front) as *const u8,
+                        (*header).size,
+                        (*header).callers,
+                    )
+                };
+
+                let corrupted = if unsafe { find_corruption(header) }.is_some() {
+                    " CORRUPTED"
+                } else {
+                    ""
+                };
+                serial_println!("{data:p}: {size} bytes{corrupted}, callers {callers:#x?}");
+
+                header = unsafe { (*header).next };
+            }
+        })
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let callers = callers();
+        let Some((outer, front)) = outer_layout(layout) else {
+            return ptr::null_mut();
+        };
+
+        let base = unsafe { self.inner.alloc(outer) };
+        if base.is_null() {
+            return base;
+        }
+
+        let header = base as *mut Header;
+        unsafe {
+            let data = base.add(front);
+            let redzone = base.add(size_of::<Header>());
+            redzone.write_bytes(REDZONE_BYTE, data.offset_from_unsigned(redzone));
+            data.write_bytes(ALLOC_POISON, layout.size());
+            data.add(layout.size())
+                .write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+
+            header.write(Header {
+                magic: LIVE_MAGIC,
+                size: layout.size(),
+                front,
+                next: ptr::null_mut(),
+                prev: ptr::null_mut(),
+                callers,
+            });
+        }
+
+        interrupts::without_interrupts(|| unsafe { self.registry.lock().insert(header) });
+
+        unsafe { base.add(front) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (outer, front) = outer_layout(layout).expect("freeing an impossible layout");
+        let base = unsafe { ptr.sub(front) };
+        let header = base as *mut Header;
+
+        match unsafe { (*header).magic } {
+            LIVE_MAGIC => {}
+            FREED_MAGIC => panic!("double free of {ptr:p} ({} bytes)", layout.size()),
+            _ => {
+                panic!("free of {ptr:p}, which is no live heap allocation or has a smashed header")
+            }
+        }
+
+        let (size, callers) = unsafe { ((*header).size, (*header).callers) };
+        assert_eq!(
+            size,
+            layout.size(),
+            "{ptr:p} freed with a different size than it was allocated with"
+        );
+
+        if let Some(offset) = unsafe { find_corruption(header) } {
+            panic!(
+                "heap corruption at offset {offset} of {ptr:p} ({size} bytes), allocated by \
+                 {callers:#x?}"
+            );
+        }
+
+        interrupts::without_interrupts(|| unsafe { self.registry.lock().remove(header) });
+
+        unsafe {
+            ptr.write_bytes(FREE_POISON, size);
+            (*header).magic = FREED_MAGIC;
+        }
+
+        let evicted = interrupts::without_interrupts(|| self.quarantine.lock().push(header, outer));
+        if let Some((header, outer)) = evicted {
+            unsafe {
+                check_quarantined(header);
+                self.inner.dealloc(header as *mut u8, outer);
+            }
+        }
+    }
+}
+
+/// Panics if a quarantined allocation was written to since it was freed
+unsafe fn check_quarantined(header: *mut Header) {
+    let (magic, size, front, callers) = unsafe {
+        (
+            (*header).magic,
+            (*header).size,
+            (*header).front,
+            (*header).callers,
+        )
+    };
+    let data = unsafe { header.byte_add(front) } as *const u8;
+
+    assert_eq!(
+        magic, FREED_MAGIC,
+        "header of freed allocation {data:p} was overwritten"
+    );
+
+    if let Some(offset) = (0..size).find(|&offset| unsafe { *data.add(offset) } != FREE_POISON) {
+        panic!(
+            "use after free at offset {offset} of {data:p} ({size} bytes), allocated by \
+             {callers:#x?}"
+        );
+    }
+    if let Some(offset) = unsafe { find_corruption(header) } {
+        panic!("heap corruption at offset {offset} of freed allocation {data:p} ({size} bytes)");
+    }
+}
+
+/// Layout of the whole allocation and the offset of the data in it
+fn outer_layout(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(align_of::<Header>());
+    // header plus the front redzone, padded so that the data stays aligned
+    let front = (size_of::<Header>() + REDZONE_SIZE).next_multiple_of(align);
+    let size = front
+        .checked_add(layout.size())?
+        .checked_add(REDZONE_SIZE)?;
+
+    Some((Layout::from_size_align(size, align).ok()?, front))
+}
+
+/// Offset relative to the data of the first redzone byte that was overwritten
+unsafe fn find_corruption(header: *mut Header) -> Option<isize> {
+    let (size, front) = unsafe { ((*header).size, (*header).front) };
+    let base = header as *const u8;
+
+    let front_redzone = size_of::<Header>()..front;
+    let back_redzone = front + size..front + size + REDZONE_SIZE;
+
+    front_redzone
+        .chain(back_redzone)
+        .find(|&offset| unsafe { *base.add(offset) } != REDZONE_BYTE)
+        .map(|offset| offset as isize - front as isize)
+}
+
+/// Return addresses of the current call chain, found by following the frame pointers
+#[inline(always)]
+fn callers() -> [usize; CALLER_DEPTH] {
+    let mut callers = [0; CALLER_DEPTH];
+
+    let mut frame: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack, preserves_flags)) };
+
+    for caller in &mut callers {
+        if frame < KERNEL_HALF_START || !frame.is_multiple_of(align_of::<usize>()) {
+            break;
+        }
+
+        // a frame starts with the caller's frame pointer, followed by the return address
+        let frame_ptr = frame as *const usize;
+        unsafe {
+            *caller = frame_ptr.add(1).read();
+            frame = frame_ptr.read();
+        }
+    }
+
+    callers
+}
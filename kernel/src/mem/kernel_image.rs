@@ -0,0 +1,147 @@
+//! W^X enforcement for the kernel.
+//!
+//! Limine maps the kernel image according to its PHDRs, but nothing guarantees that it did so as
+//! tightly as the sections allow. [`init`] reapplies the permissions of every section using the
+//! boundaries exported by the linker script and marks the rest of the kernel half no-execute, so
+//! code only ever runs from `.text`. [`audit`] then checks that no page is both writable and
+//! executable.
+
+use core::arch::x86_64::__cpuid;
+
+use limine::request::ExecutableAddressRequest;
+use x86_64::{
+    VirtAddr,
+    instructions::{interrupts, tlb},
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{PageTable, PageTableFlags},
+};
+
+use super::{MAPPER, address_space::KERNEL_ENTRIES, vmm};
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static EXECUTABLE_ADDRESS_REQUEST: ExecutableAddressRequest = ExecutableAddressRequest::new();
+
+// defined in linker-x86_64.ld, every section starts on a page of its own
+unsafe extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+/// Enables no-execute support and tightens the permissions of the kernel mappings
+pub(super) fn init() {
+    assert!(
+        supports_no_execute(),
+        "cpu does not support no-execute pages"
+    );
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+
+    let virtual_base = EXECUTABLE_ADDRESS_REQUEST
+        .get_response()
+        .expect("missing executable address")
+        .virtual_base();
+    assert!(
+        virtual_base <= VirtAddr::from_ptr(&raw const __text_start).as_u64(),
+        "kernel sections lie outside the kernel image"
+    );
+
+    let sections = [
+        (
+            &raw const __text_start,
+            &raw const __text_end,
+            PageTableFlags::empty(),
+        ),
+        (
+            &raw const __rodata_start,
+            &raw const __rodata_end,
+            PageTableFlags::NO_EXECUTE,
+        ),
+        (
+            &raw const __data_start,
+            &raw const __data_end,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        ),
+    ];
+
+    for (start, end, flags) in sections {
+        let (start, end) = (VirtAddr::from_ptr(start), VirtAddr::from_ptr(end));
+        // global, so that switching address spaces keeps the image in the tlb
+        let flags = PageTableFlags::PRESENT | PageTableFlags::GLOBAL | flags;
+        vmm::protect_range(start, end - start, flags).expect("failed to protect a kernel section");
+    }
+
+    // the hhdm and every region share the kernel half with the image, none of them holds code
+    let image_entry = usize::from(VirtAddr::new(virtual_base).p4_index());
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.get().expect("mapper not initialized").lock();
+        let level_4_table = mapper.level_4_table_mut();
+
+        for i in KERNEL_ENTRIES.filter(|&i| i != image_entry) {
+            let entry = &mut level_4_table[i];
+            if entry.flags().contains(PageTableFlags::PRESENT) {
+                entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+            }
+        }
+
+        tlb::flush_all();
+    });
+
+    audit();
+}
+
+/// Panics if any page of the kernel page tables is both writable and executable
+pub fn audit() {
+    interrupts::without_interrupts(|| {
+        let mapper = MAPPER.get().expect("mapper not initialized").lock();
+        if let Some(addr) = find_writable_executable(mapper.level_4_table(), 4, 0, true) {
+            panic!("page at {addr:#x} is mapped writable and executable");
+        }
+    })
+}
+
+/// First page below `table` whose effective permissions allow both writes and execution.
+/// `writable` is whether all entries above the table allow writes, the ones above are known to
+/// allow execution.
+fn find_writable_executable(
+    table: &PageTable,
+    level: u8,
+    base: u64,
+    writable: bool,
+) -> Option<VirtAddr> {
+    for (i, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::NO_EXECUTE) {
+            continue;
+        }
+
+        let writable = writable && flags.contains(PageTableFlags::WRITABLE);
+        if !writable {
+            // nothing below a read-only entry can be written to
+            continue;
+        }
+
+        // 9 bits of the address are translated per level
+        let addr = base | (i as u64) << (12 + (level as u64 - 1) * 9);
+
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return Some(VirtAddr::new_truncate(addr));
+        }
+
+        let child: &PageTable = unsafe { &*super::phys_to_virt(entry.addr()).as_ptr() };
+        if let Some(addr) = find_writable_executable(child, level - 1, addr, writable) {
+            return Some(addr);
+        }
+    }
+
+    None
+}
+
+fn supports_no_execute() -> bool {
+    #[allow(unused_unsafe)]
+    let edx = unsafe { __cpuid(0x8000_0001) }.edx;
+    edx & (1 << 20) != 0
+}
@@ -0,0 +1,127 @@
+//! Mapping of device memory into the kernel address space.
+//!
+//! Every mapping gets its own region from [`super::vma`]. Requests that fall inside an existing
+//! mapping with the same cache mode share it, the mapping goes away when the last user calls
+//! [`iounmap`].
+
+use alloc::vec::Vec;
+
+use spin::Mutex;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::interrupts,
+    structures::paging::{PageSize, PageTableFlags, Size2MiB, Size4KiB},
+};
+
+use super::{
+    vma::{self, Backing, RegionKind, VmaError},
+    vmm::{self, CacheMode, VmmError},
+};
+
+static IO_MAPPINGS: Mutex<Vec<IoMapping>> = Mutex::new(Vec::new());
+
+struct IoMapping {
+    phys: PhysAddr,
+    virt: VirtAddr,
+    size: u64,
+    cache_mode: CacheMode,
+    ref_count: usize,
+}
+
+impl IoMapping {
+    fn covers(&self, phys: PhysAddr, size: u64, cache_mode: CacheMode) -> bool {
+        self.cache_mode == cache_mode && phys >= self.phys && phys + size <= self.phys + self.size
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioError {
+    Vma(VmaError),
+    Vmm(VmmError),
+    /// The address doesn't belong to any mapping created by [`ioremap`]
+    NotMapped(VirtAddr),
+}
+
+impl From<VmaError> for MmioError {
+    fn from(err: VmaError) -> Self {
+        MmioError::Vma(err)
+    }
+}
+
+impl From<VmmError> for MmioError {
+    fn from(err: VmmError) -> Self {
+        MmioError::Vmm(err)
+    }
+}
+
+/// Maps `len` bytes of physical memory starting at `phys` and returns the virtual address of
+/// `phys`
+pub fn ioremap(phys: PhysAddr, len: u64, cache_mode: CacheMode) -> Result<VirtAddr, MmioError> {
+    let start = phys.align_down(Size4KiB::SIZE);
+    let size = (phys + len.max(1)).align_up(Size4KiB::SIZE) - start;
+
+    interrupts::without_interrupts(|| {
+        let mut mappings = IO_MAPPINGS.lock();
+
+        if let Some(mapping) = mappings
+            .iter_mut()
+            .find(|m| m.covers(start, size, cache_mode))
+        {
+            mapping.ref_count += 1;
+            return Ok(mapping.virt + (phys - mapping.phys));
+        }
+
+        // large mappings get aligned so that they can use huge pages
+        let align = if size >= Size2MiB::SIZE {
+            Size2MiB::SIZE
+        } else {
+            Size4KiB::SIZE
+        };
+        let virt = vma::reserve(size, align, RegionKind::Mmio, Backing::Mapped)?;
+
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_EXECUTE
+            | cache_mode.flags();
+        if let Err(err) = vmm::map_range(start, virt, size, flags) {
+            // undo the part that did get mapped
+            vmm::unmap_partial_mmio_range(virt, size)?;
+            vma::release(virt)?;
+            return Err(err.into());
+        }
+
+        mappings.push(IoMapping {
+            phys: start,
+            virt,
+            size,
+            cache_mode,
+            ref_count: 1,
+        });
+
+        Ok(virt + (phys - start))
+    })
+}
+
+/// Drops a reference to the mapping containing `virt`, unmapping it once no one uses it anymore
+pub fn iounmap(virt: VirtAddr) -> Result<(), MmioError> {
+    interrupts::without_interrupts(|| {
+        let mut mappings = IO_MAPPINGS.lock();
+
+        let index = mappings
+            .iter()
+            .position(|m| (m.virt..m.virt + m.size).contains(&virt))
+            .ok_or(MmioError::NotMapped(virt))?;
+
+        let mapping = &mut mappings[index];
+        mapping.ref_count -= 1;
+        if mapping.ref_count > 0 {
+            return Ok(());
+        }
+
+        let mapping = mappings.swap_remove(index);
+        vmm::unmap_mmio_range(mapping.virt, mapping.size)?;
+        vma::release(mapping.virt)?;
+
+        Ok(())
+    })
+}
@@ -1,5 +1,22 @@
+pub mod address_space;
+pub mod dma;
+pub mod fault;
 pub mod frame_allocator;
 pub mod heap;
+#[cfg(feature = "heap-debug")]
+pub mod heap_debug;
+pub mod kernel_image;
+pub mod mmio;
+pub mod numa;
+pub mod paging;
+pub mod reclaim;
+pub mod slab;
+pub mod stack;
+pub mod stats;
+pub mod swap;
+pub mod vma;
+pub mod vmalloc;
+pub mod vmm;
 
 use core::ops::DerefMut;
 
@@ -8,7 +25,7 @@ use limine::request::HhdmRequest;
 use spin::{Mutex, Once};
 use x86_64::{
     PhysAddr, VirtAddr,
-    registers::control::Cr3,
+    instructions::interrupts,
     structures::paging::{OffsetPageTable, PageTable, Translate},
 };
 
@@ -20,22 +37,26 @@ static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
 
 pub static MAPPER: Once<Mutex<OffsetPageTable>> = Once::new();
 
+// set by paging::init, with 5-level paging it differs from the offset limine reports
+static HHDM_OFFSET: Once<u64> = Once::new();
+
 pub fn init() {
     unsafe {
+        paging::init();
+
         let level_4_table = active_level_4_table();
         MAPPER.call_once(|| {
             Mutex::new(OffsetPageTable::new(
                 level_4_table,
-                VirtAddr::new(
-                    HHDM_REQUEST
-                        .get_response()
-                        .expect("hhdm not enabled")
-                        .offset(),
-                ),
+                VirtAddr::new(hhdm_offset()),
             ))
         });
 
         frame_allocator::init_frame_allocator();
+        stats::init();
+        address_space::init();
+        kernel_image::init();
+        vma::init();
 
         heap::init_heap(
             MAPPER.get_unchecked().lock().deref_mut(),
@@ -49,7 +70,7 @@ pub fn init() {
 ///
 /// This function must only be called once to avoid aliasing &mut references.
 unsafe fn active_level_4_table() -> &'static mut PageTable {
-    let (level_4_page_frame, _) = Cr3::read();
+    let level_4_page_frame = paging::pml4_of(paging::kernel_root());
 
     let virt = phys_to_virt(level_4_page_frame.start_address());
     let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
@@ -57,19 +78,15 @@ unsafe fn active_level_4_table() -> &'static mut PageTable {
     unsafe { &mut *page_table_ptr }
 }
 
+pub fn hhdm_offset() -> u64 {
+    *HHDM_OFFSET.get().expect("paging not initialized")
+}
+
 pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
-    VirtAddr::new(
-        addr.as_u64()
-            + HHDM_REQUEST
-                .get_response()
-                .expect("hhdm not enabled")
-                .offset(),
-    )
+    VirtAddr::new(addr.as_u64() + hhdm_offset())
 }
 
 pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
-    use x86_64::instructions::interrupts;
-
     interrupts::without_interrupts(|| {
         MAPPER
             .get()
@@ -78,47 +95,3 @@ pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
             .translate_addr(addr)
     })
 }
-
-#[macro_export]
-macro_rules! map_page {
-    ($phys:expr, $virt:expr, $size:ty, $flags:expr) => {
-        // macros expect everything to be imported each time they're used in a new file, so best to hardcode paths
-        let phys_frame = x86_64::structures::paging::PhysFrame::containing_address($phys);
-        let page = x86_64::structures::paging::Page::<$size>::containing_address($virt);
-
-        x86_64::instructions::interrupts::without_interrupts(|| {
-            // suppress warnings if this macro is called from an unsafe fn
-            #[allow(unused_unsafe)]
-            let res = unsafe {
-                // in case this macro is called from a file that doesn't import this
-                use x86_64::structures::paging::Mapper as MacroMapper;
-
-                $crate::mem::MAPPER.get().expect("mapper not initialized").lock().map_to(
-                    page,
-                    phys_frame,
-                    $flags,
-                    &mut *$crate::mem::FRAME_ALLOCATOR.get().expect("frame allocator not initialized").lock(),
-                )
-            };
-
-            let flush = match res{
-               Ok(flush) => Some(flush),
-                Err(e) => match e {
-                    x86_64::structures::paging::mapper::MapToError::FrameAllocationFailed => panic!("Out of memory"),
-                    x86_64::structures::paging::mapper::MapToError::PageAlreadyMapped(_) => {
-                        // Skip mapping as page already exists
-                        None
-                    }
-                    x86_64::structures::paging::mapper::MapToError::ParentEntryHugePage => {
-                        // Skip mapping as page already exists
-                        None
-                    }
-                },
-            };
-
-            if let Some(flush) = flush {
-                flush.flush();
-            }
-        });
-    };
-}
@@ -0,0 +1,211 @@
+//! NUMA topology.
+//!
+//! The firmware describes the topology in terms of proximity domains, arbitrary numbers that
+//! group cpus and memory ranges. Nodes are those domains numbered densely, in the order they were
+//! first seen, so that they can index arrays. Machines without an SRAT get a single node holding
+//! everything, machines with more domains than the frame allocator has pools get the extra ones
+//! merged into the closest of the others.
+
+use alloc::{vec, vec::Vec};
+
+use spin::Once;
+use x86_64::{PhysAddr, instructions::interrupts};
+
+use super::{FRAME_ALLOCATOR, frame_allocator::MAX_NODES};
+
+/// Distance of a node to itself, distances between nodes are relative to this
+pub const LOCAL_DISTANCE: u8 = 10;
+/// Distance assumed between two different nodes if the firmware gives none
+pub const REMOTE_DISTANCE: u8 = 20;
+
+static TOPOLOGY: Once<NumaTopology> = Once::new();
+
+#[derive(Debug, Clone, Copy)]
+pub struct CpuAffinity {
+    pub apic_id: u32,
+    pub node: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryAffinity {
+    pub base: PhysAddr,
+    pub length: u64,
+    pub node: usize,
+    pub hotpluggable: bool,
+}
+
+impl MemoryAffinity {
+    pub fn contains(&self, addr: PhysAddr) -> bool {
+        (self.base.as_u64()..self.base.as_u64() + self.length).contains(&addr.as_u64())
+    }
+}
+
+/// Starts out without any node, nodes show up as cpus and memory are added
+#[derive(Debug, Clone, Default)]
+pub struct NumaTopology {
+    /// Proximity domain of every node
+    domains: Vec<u32>,
+    cpus: Vec<CpuAffinity>,
+    memory: Vec<MemoryAffinity>,
+    /// Row-major matrix of the distances between nodes, empty if the firmware gives none
+    distances: Vec<u8>,
+}
+
+impl NumaTopology {
+    /// A single node that all cpus and memory belong to
+    pub fn uniform() -> Self {
+        Self {
+            domains: vec![0],
+            ..Self::default()
+        }
+    }
+
+    /// The node of a proximity domain, which becomes a new node the first time it is seen
+    pub fn node_of_domain(&mut self, domain: u32) -> usize {
+        match self.domains.iter().position(|&d| d == domain) {
+            Some(node) => node,
+            None => {
+                self.domains.push(domain);
+                self.distances.clear();
+                self.domains.len() - 1
+            }
+        }
+    }
+
+    pub fn add_cpu(&mut self, domain: u32, apic_id: u32) {
+        let node = self.node_of_domain(domain);
+        self.cpus.push(CpuAffinity { apic_id, node });
+    }
+
+    pub fn add_memory(&mut self, domain: u32, base: PhysAddr, length: u64, hotpluggable: bool) {
+        let node = self.node_of_domain(domain);
+        self.memory.push(MemoryAffinity {
+            base,
+            length,
+            node,
+            hotpluggable,
+        });
+    }
+
+    /// Sets the distance between every pair of nodes from the distance between their domains.
+    /// Must be called after all nodes have been added.
+    pub fn set_distances(&mut self, distance: impl Fn(u32, u32) -> u8) {
+        self.distances = self
+            .domains
+            .iter()
+            .flat_map(|&from| self.domains.iter().map(move |&to| (from, to)))
+            .map(|(from, to)| distance(from, to))
+            .collect();
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn domain(&self, node: usize) -> u32 {
+        self.domains[node]
+    }
+
+    pub fn cpus(&self) -> &[CpuAffinity] {
+        &self.cpus
+    }
+
+    pub fn memory(&self) -> &[MemoryAffinity] {
+        &self.memory
+    }
+
+    pub fn node_of_cpu(&self, apic_id: u32) -> Option<usize> {
+        self.cpus
+            .iter()
+            .find(|cpu| cpu.apic_id == apic_id)
+            .map(|cpu| cpu.node)
+    }
+
+    pub fn node_of_addr(&self, addr: PhysAddr) -> Option<usize> {
+        self.memory
+            .iter()
+            .find(|range| range.contains(addr))
+            .map(|range| range.node)
+    }
+
+    /// Relative cost of accessing memory of node `to` from node `from`, [`LOCAL_DISTANCE`] being
+    /// the cost of local accesses
+    pub fn distance(&self, from: usize, to: usize) -> u8 {
+        match self.distances.get(from * self.node_count() + to) {
+            Some(&distance) => distance,
+            None if from == to => LOCAL_DISTANCE,
+            None => REMOTE_DISTANCE,
+        }
+    }
+
+    /// Merges every node from `max_nodes` on into the closest of the first `max_nodes` nodes
+    fn folded(&self, max_nodes: usize) -> Self {
+        let target = |node: usize| {
+            if node < max_nodes {
+                return node;
+            }
+            (0..max_nodes)
+                .min_by_key(|&to| (self.distance(node, to), to))
+                .expect("folding into no nodes")
+        };
+
+        let distances = if self.distances.is_empty() {
+            Vec::new()
+        } else {
+            (0..max_nodes)
+                .flat_map(|from| (0..max_nodes).map(move |to| (from, to)))
+                .map(|(from, to)| self.distance(from, to))
+                .collect()
+        };
+
+        Self {
+            domains: self.domains[..max_nodes].to_vec(),
+            cpus: self
+                .cpus
+                .iter()
+                .map(|cpu| CpuAffinity {
+                    node: target(cpu.node),
+                    ..*cpu
+                })
+                .collect(),
+            memory: self
+                .memory
+                .iter()
+                .map(|range| MemoryAffinity {
+                    node: target(range.node),
+                    ..*range
+                })
+                .collect(),
+            distances,
+        }
+    }
+}
+
+/// Makes `topology` the system topology and splits the frame allocator's pools by node, with
+/// allocations preferring the node of the cpu with `boot_apic_id`
+pub fn init(topology: NumaTopology, boot_apic_id: u32) {
+    let topology = if topology.node_count() > MAX_NODES {
+        crate::warning!(
+            "{} numa nodes, merging all but {MAX_NODES} into their closest neighbours",
+            topology.node_count()
+        );
+        topology.folded(MAX_NODES)
+    } else {
+        topology
+    };
+
+    let topology = TOPOLOGY.call_once(|| topology);
+    let local_node = topology.node_of_cpu(boot_apic_id).unwrap_or(0);
+
+    interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR
+            .get()
+            .expect("frame allocator not initialized")
+            .lock()
+            .set_topology(topology, local_node)
+    });
+}
+
+pub fn topology() -> &'static NumaTopology {
+    TOPOLOGY.get().expect("numa topology not initialized")
+}
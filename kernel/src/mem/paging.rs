@@ -0,0 +1,255 @@
+//! Paging mode selection.
+//!
+//! Limine is asked for 5-level paging, which it enables whenever the cpu supports LA57. The rest
+//! of the memory manager only knows 4-level tables and 48 bit addresses though, so with 5 levels
+//! only two PML5 entries are used: the last one points to the kernel PML4, which translates the
+//! kernel half like it would with 4 levels, and the first one points to the PML4 of the active
+//! address space, which only holds user mappings. Everything between the two stays unmapped.
+//!
+//! That leaves the same limits as 4-level paging: user space ends at 128 TiB, and as physical
+//! memory has to fit into the hhdm window of the kernel PML4, at most 64 TiB of it is used.
+//!
+//! With 5 levels Limine places the hhdm outside of that window, so [`init`] maps physical memory
+//! into the window as well, at the address the hhdm has with 4 levels. Pointers Limine hands out
+//! still point into its own hhdm, [`bootloader_virt_to_phys`] translates those.
+
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use limine::{paging::Mode, request::PagingModeRequest};
+use spin::Once;
+use x86_64::{
+    PhysAddr,
+    instructions::tlb,
+    registers::control::{Cr3, Cr4, Cr4Flags},
+    structures::paging::{FrameAllocator, FrameDeallocator, PageTable, PageTableFlags, PhysFrame},
+};
+
+use super::{HHDM_OFFSET, HHDM_REQUEST, frame_allocator::KernelFrameAllocator};
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static PAGING_MODE_REQUEST: PagingModeRequest = PagingModeRequest::new()
+    .with_mode(Mode::FIVE_LEVEL)
+    .with_min_mode(Mode::FOUR_LEVEL);
+
+/// PML4 entries physical memory is mapped through with 5-level paging, below the kernel regions
+const HHDM_WINDOW: Range<usize> = 256..384;
+/// Start of the hhdm window, where Limine puts the hhdm with 4-level paging
+const HHDM_WINDOW_START: u64 = 0xffff_8000_0000_0000;
+/// Memory covered by one PML4 entry
+const PML4_ENTRY_SIZE: u64 = 1 << 39;
+
+const KERNEL_ROOT_ENTRY_FLAGS: PageTableFlags =
+    PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+const USER_ROOT_ENTRY_FLAGS: PageTableFlags =
+    KERNEL_ROOT_ENTRY_FLAGS.union(PageTableFlags::USER_ACCESSIBLE);
+
+static MODE: Once<PagingMode> = Once::new();
+static BOOTLOADER_HHDM_OFFSET: Once<u64> = Once::new();
+/// Physical address of the table CR3 points to while no address space is active
+static KERNEL_ROOT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    FourLevel,
+    FiveLevel,
+}
+
+/// Detects the paging mode and sets up the hhdm the rest of the kernel uses
+///
+/// # Safety
+///
+/// Must be called once, before anything translates between physical and virtual addresses
+pub(super) unsafe fn init() {
+    let bootloader_offset = *BOOTLOADER_HHDM_OFFSET.call_once(|| {
+        HHDM_REQUEST
+            .get_response()
+            .expect("hhdm not enabled")
+            .offset()
+    });
+
+    let mode = if Cr4::read().contains(Cr4Flags::L5_PAGING) {
+        PagingMode::FiveLevel
+    } else {
+        PagingMode::FourLevel
+    };
+    if let Some(response) = PAGING_MODE_REQUEST.get_response() {
+        let reported = if response.mode() == Mode::FIVE_LEVEL {
+            PagingMode::FiveLevel
+        } else {
+            PagingMode::FourLevel
+        };
+        assert_eq!(mode, reported, "limine reported the wrong paging mode");
+    }
+    MODE.call_once(|| mode);
+
+    let (root, _) = Cr3::read();
+    KERNEL_ROOT.store(root.start_address().as_u64(), Ordering::Relaxed);
+
+    let offset = match mode {
+        PagingMode::FourLevel => bootloader_offset,
+        PagingMode::FiveLevel => {
+            unsafe { build_hhdm_window(root, bootloader_offset) };
+            HHDM_WINDOW_START
+        }
+    };
+    HHDM_OFFSET.call_once(|| offset);
+}
+
+pub fn mode() -> PagingMode {
+    *MODE.get().expect("paging not initialized")
+}
+
+/// Physical address behind a pointer into the hhdm Limine set up, like the ones in its responses
+pub fn bootloader_virt_to_phys(ptr: *const u8) -> PhysAddr {
+    let offset = BOOTLOADER_HHDM_OFFSET
+        .get()
+        .expect("paging not initialized");
+    PhysAddr::new(ptr as u64 - offset)
+}
+
+/// Whether the PML4 of an address space translates the kernel half too, which it has to with
+/// 4-level paging. With 5 levels the kernel half goes through the kernel PML4 instead.
+pub(super) fn pml4_maps_kernel() -> bool {
+    mode() == PagingMode::FourLevel
+}
+
+/// Creates the table CR3 points to for the kernel PML4 `pml4`, which is `pml4` itself with
+/// 4-level paging
+pub(super) fn new_kernel_root(
+    pml4: PhysFrame,
+    frame_allocator: &mut KernelFrameAllocator,
+) -> Option<PhysFrame> {
+    if mode() == PagingMode::FourLevel {
+        return Some(pml4);
+    }
+
+    let root = allocate_root(frame_allocator)?;
+    let table = unsafe { table_at(root.start_address()) };
+    table[511].set_frame(pml4, KERNEL_ROOT_ENTRY_FLAGS);
+
+    Some(root)
+}
+
+/// Creates the table CR3 points to for an address space whose user half `pml4` translates. With
+/// 4-level paging that is `pml4` itself, which then has to hold the kernel entries as well.
+pub(super) fn new_root(
+    pml4: PhysFrame,
+    frame_allocator: &mut KernelFrameAllocator,
+) -> Option<PhysFrame> {
+    if mode() == PagingMode::FourLevel {
+        return Some(pml4);
+    }
+
+    let root = allocate_root(frame_allocator)?;
+    let table = unsafe { table_at(root.start_address()) };
+    table[0].set_frame(pml4, USER_ROOT_ENTRY_FLAGS);
+    table[511].set_frame(pml4_of(kernel_root()), KERNEL_ROOT_ENTRY_FLAGS);
+
+    Some(root)
+}
+
+fn allocate_root(frame_allocator: &mut KernelFrameAllocator) -> Option<PhysFrame> {
+    let root = frame_allocator.allocate_frame()?;
+    unsafe { table_at(root.start_address()) }.zero();
+    Some(root)
+}
+
+/// Frees a root created by [`new_root`] or [`new_kernel_root`], but not the PML4 below it
+///
+/// # Safety
+///
+/// The root must not be loaded anymore
+pub(super) unsafe fn free_root(root: PhysFrame, frame_allocator: &mut KernelFrameAllocator) {
+    if mode() == PagingMode::FiveLevel {
+        unsafe { frame_allocator.deallocate_frame(root) };
+    }
+}
+
+/// The PML4 translating the kernel half of the hierarchy under `root`
+pub(super) fn pml4_of(root: PhysFrame) -> PhysFrame {
+    match mode() {
+        PagingMode::FourLevel => root,
+        PagingMode::FiveLevel => {
+            let table = unsafe { table_at(root.start_address()) };
+            table[511].frame().expect("root table without a pml4")
+        }
+    }
+}
+
+pub(super) fn kernel_root() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_ROOT.load(Ordering::Relaxed)))
+}
+
+/// Loads `root` into CR3
+///
+/// # Safety
+///
+/// `root` must map the kernel like the current root does
+pub(super) unsafe fn switch_root(root: PhysFrame) {
+    let (_, flags) = Cr3::read();
+    unsafe { Cr3::write(root, flags) };
+}
+
+/// Replaces the kernel page tables with the hierarchy under `root`
+///
+/// # Safety
+///
+/// `root` must map the kernel like the current root does and no address space may be active
+pub(super) unsafe fn switch_kernel_root(root: PhysFrame) {
+    unsafe { switch_root(root) };
+    KERNEL_ROOT.store(root.start_address().as_u64(), Ordering::Relaxed);
+}
+
+/// Maps physical memory into the 48 bit window of the PML4 in the last entry of `root`
+///
+/// # Safety
+///
+/// `root` must be the PML5 Limine built, with its hhdm at `bootloader_offset`
+unsafe fn build_hhdm_window(root: PhysFrame, bootloader_offset: u64) {
+    assert!(
+        bootloader_offset.is_multiple_of(PML4_ENTRY_SIZE),
+        "hhdm isn't aligned to a pml4 entry"
+    );
+
+    // the bootloader hhdm is the only mapping of the page tables so far
+    let table =
+        |addr: PhysAddr| unsafe { &mut *((bootloader_offset + addr.as_u64()) as *mut PageTable) };
+
+    let pml5 = table(root.start_address());
+    let hhdm_entry = (bootloader_offset >> 48) as usize & 0x1ff;
+    assert_ne!(hhdm_entry, 511, "hhdm shares a pml5 entry with the kernel");
+    let kernel_pml4 = pml5[511].addr();
+    let hhdm_pml4 = pml5[hhdm_entry].addr();
+    let hhdm_start = (bootloader_offset >> 39) as usize & 0x1ff;
+
+    let (kernel_pml4, hhdm_pml4) = (table(kernel_pml4), table(hhdm_pml4));
+    for (i, entry) in hhdm_pml4.iter().enumerate().skip(hhdm_start) {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        let slot = HHDM_WINDOW.start + (i - hhdm_start);
+        assert!(
+            HHDM_WINDOW.contains(&slot),
+            "physical memory doesn't fit into the hhdm window"
+        );
+        assert!(
+            !kernel_pml4[slot].flags().contains(PageTableFlags::PRESENT),
+            "hhdm window is already in use"
+        );
+        kernel_pml4[slot] = entry.clone();
+    }
+
+    tlb::flush_all();
+}
+
+/// # Safety
+///
+/// `addr` must point to a page table that nothing else accesses while the reference lives
+unsafe fn table_at<'a>(addr: PhysAddr) -> &'a mut PageTable {
+    unsafe { &mut *super::phys_to_virt(addr).as_mut_ptr() }
+}
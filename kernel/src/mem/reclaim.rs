@@ -0,0 +1,171 @@
+use core::arch::asm;
+
+use limine::memory_map::EntryType;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::interrupts,
+    structures::paging::{
+        FrameAllocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame, Translate,
+    },
+};
+
+use super::{
+    FRAME_ALLOCATOR, MAPPER, frame_allocator, frame_allocator::KernelFrameAllocator, paging,
+};
+use crate::println;
+
+/// Hands `BOOTLOADER_RECLAIMABLE` memory over to the frame allocator.
+///
+/// The page tables Limine built live in that memory, so they are moved into freshly allocated
+/// frames first. The region holding the current stack is kept in case it is still the boot stack.
+///
+/// # Safety
+///
+/// Every Limine response must have been consumed before calling this, nothing may hold references
+/// into them afterwards
+pub unsafe fn reclaim_bootloader_memory() {
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.get().expect("mapper not initialized").lock();
+        let mut frame_allocator = FRAME_ALLOCATOR
+            .get()
+            .expect("frame allocator not initialized")
+            .lock();
+
+        let stack_pointer: u64;
+        unsafe {
+            asm!(
+            "mov {}, rsp",
+            out(reg) stack_pointer
+            );
+        }
+        let stack_frame = mapper
+            .translate_addr(VirtAddr::new(stack_pointer))
+            .expect("stack is not mapped");
+        let level_4_table = mapper
+            .translate_addr(VirtAddr::from_ptr(mapper.level_4_table()))
+            .expect("level 4 table is not mapped");
+
+        unsafe {
+            let level_4_table = relocate_page_tables(
+                PhysFrame::containing_address(level_4_table),
+                4,
+                &mut frame_allocator,
+            );
+            let root = paging::new_kernel_root(level_4_table, &mut frame_allocator)
+                .expect("out of memory while copying page tables");
+            paging::switch_kernel_root(root);
+
+            *mapper = OffsetPageTable::new(
+                &mut *super::phys_to_virt(level_4_table.start_address()).as_mut_ptr(),
+                mapper.phys_offset(),
+            );
+        }
+
+        let reclaimed = unsafe {
+            reclaim_regions(
+                &mut frame_allocator,
+                EntryType::BOOTLOADER_RECLAIMABLE,
+                Some(stack_frame),
+            )
+        };
+
+        println!("reclaimed {} KiB of bootloader memory", reclaimed / 1024);
+    })
+}
+
+/// Hands `ACPI_RECLAIMABLE` memory over to the frame allocator.
+///
+/// # Safety
+///
+/// The ACPI tables live in that memory, so nothing may access them through
+/// [`crate::arch::acpi::ACPI_PLATFORM`] afterwards. Must be called before
+/// [`reclaim_bootloader_memory`] as the memory map is needed to find the regions.
+pub unsafe fn reclaim_acpi_memory() {
+    interrupts::without_interrupts(|| {
+        let mut frame_allocator = FRAME_ALLOCATOR
+            .get()
+            .expect("frame allocator not initialized")
+            .lock();
+
+        let reclaimed =
+            unsafe { reclaim_regions(&mut frame_allocator, EntryType::ACPI_RECLAIMABLE, None) };
+
+        println!("reclaimed {} KiB of acpi memory", reclaimed / 1024);
+    })
+}
+
+/// Frees every region of the given type except the one containing `keep`, returns the number of
+/// bytes freed
+unsafe fn reclaim_regions(
+    frame_allocator: &mut KernelFrameAllocator,
+    entry_type: EntryType,
+    keep: Option<PhysAddr>,
+) -> u64 {
+    let mut reclaimed = 0;
+
+    for region in frame_allocator::memory_map()
+        .iter()
+        .filter(|r| r.entry_type == entry_type)
+    {
+        let (start, end) = (region.base, region.base + region.length);
+        if keep.is_some_and(|addr| (start..end).contains(&addr.as_u64())) {
+            continue;
+        }
+
+        unsafe { frame_allocator.free_range(start, end) };
+        reclaimed += region.length;
+    }
+
+    reclaimed
+}
+
+/// Moves `table` and the page tables below it into new frames if they live in bootloader memory,
+/// returns where `table` ended up. Tables the kernel allocated itself stay where they are, and the
+/// mapped frames are shared.
+unsafe fn relocate_page_tables(
+    table: PhysFrame,
+    level: u8,
+    frame_allocator: &mut KernelFrameAllocator,
+) -> PhysFrame {
+    let frame = if is_bootloader_memory(table) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .expect("out of memory while copying page tables");
+        unsafe {
+            let old_table: &PageTable = &*super::phys_to_virt(table.start_address()).as_ptr();
+            *super::phys_to_virt(frame.start_address()).as_mut_ptr() = old_table.clone();
+        }
+        frame
+    } else {
+        table
+    };
+
+    if level == 1 {
+        return frame;
+    }
+
+    let table: &mut PageTable =
+        unsafe { &mut *super::phys_to_virt(frame.start_address()).as_mut_ptr() };
+    for entry in table.iter_mut() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            continue;
+        }
+
+        let child = PhysFrame::containing_address(entry.addr());
+        let new_child = unsafe { relocate_page_tables(child, level - 1, frame_allocator) };
+        if new_child != child {
+            entry.set_addr(new_child.start_address(), flags);
+        }
+    }
+
+    frame
+}
+
+fn is_bootloader_memory(frame: PhysFrame) -> bool {
+    let addr = frame.start_address().as_u64();
+    frame_allocator::memory_map().iter().any(|region| {
+        region.entry_type == EntryType::BOOTLOADER_RECLAIMABLE
+            && (region.base..region.base + region.length).contains(&addr)
+    })
+}
@@ -0,0 +1,477 @@
+//! Object caches for fixed-size kernel objects.
+//!
+//! A cache carves slabs out of frames from the frame allocator and hands out objects of a single
+//! layout. Objects are constructed once when their slab is created and must be freed in their
+//! constructed state, so reusing an object skips the initialization. Empty slabs stay cached until
+//! [`shrink_all`] hands them back to the frame allocator under memory pressure.
+//!
+//! A cache lock is never held while taking the frame allocator, so caches can be used by code
+//! that already holds the frame allocator through [`SlabCache::alloc_locked`].
+//!
+//! Caches are meant to be statics:
+//!
+//! ```ignore
+//! static NODE_CACHE: SlabCache = SlabCache::new("node", Layout::new::<Node>(), None);
+//!
+//! let node = Box::new_in(Node::default(), &NODE_CACHE);
+//! ```
+
+use alloc::vec::Vec;
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    ptr::{self, NonNull},
+};
+
+use spin::{Mutex, Once};
+use x86_64::{PhysAddr, instructions::interrupts, structures::paging::PhysFrame};
+
+use super::{FRAME_ALLOCATOR, frame_allocator::KernelFrameAllocator};
+
+const FRAME_SIZE: usize = 4096;
+/// Largest slab, 2^4 frames (64 KiB)
+const MAX_SLAB_ORDER: usize = 4;
+/// Slabs grow until at least this many objects fit, unless they hit [`MAX_SLAB_ORDER`]
+const MIN_OBJECTS: usize = 8;
+/// Capacity of the free bitmap in the slab header
+const MAX_OBJECTS: usize = 512;
+
+const MAX_CACHES: usize = 32;
+
+/// Large enough for a full ethernet frame
+pub const NET_BUFFER_SIZE: usize = 2048;
+
+/// Receive and transmit buffers of network drivers
+pub static NET_BUFFER_CACHE: SlabCache =
+    SlabCache::new("net_buffer", Layout::new::<[u8; NET_BUFFER_SIZE]>(), None);
+
+// a fixed array so that registering doesn't allocate
+static CACHES: Mutex<[Option<&'static SlabCache>; MAX_CACHES]> = Mutex::new([None; MAX_CACHES]);
+
+/// Snapshot of a cache's usage
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slab_size: usize,
+    pub slabs: usize,
+    /// Slabs without any live object, released by [`shrink_all`]
+    pub empty_slabs: usize,
+    pub active_objects: usize,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+/// Lives at the start of every slab, the objects follow it
+struct SlabHeader {
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    in_use: usize,
+    /// Set bits mark free objects
+    free: [u64; MAX_OBJECTS / 64],
+}
+
+/// Doubly linked list of slabs
+struct SlabList {
+    head: *mut SlabHeader,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, slab: *mut SlabHeader) {
+        unsafe {
+            (*slab).prev = ptr::null_mut();
+            (*slab).next = self.head;
+            if !self.head.is_null() {
+                (*self.head).prev = slab;
+            }
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut SlabHeader) {
+        unsafe {
+            let (next, prev) = ((*slab).next, (*slab).prev);
+            if prev.is_null() {
+                self.head = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+        self.len -= 1;
+    }
+}
+
+struct CacheInner {
+    partial: SlabList,
+    full: SlabList,
+    empty: SlabList,
+    active_objects: usize,
+    allocations: u64,
+    frees: u64,
+}
+
+impl CacheInner {
+    /// Puts a slab an object was just taken from on the list matching its usage
+    unsafe fn push_slab(&mut self, slab: *mut SlabHeader, objects_per_slab: usize) {
+        unsafe {
+            if (*slab).in_use == objects_per_slab {
+                self.full.push(slab);
+            } else {
+                self.partial.push(slab);
+            }
+        }
+    }
+}
+
+// the slabs are only reachable through the cache, which is behind a lock
+unsafe impl Send for CacheInner {}
+
+pub struct SlabCache {
+    name: &'static str,
+    /// Distance between two objects
+    stride: usize,
+    object_size: usize,
+    /// Alignment of every object, the first one is only aligned to this past the header
+    align: usize,
+    /// Offset of the first object, right after the header
+    first_object: usize,
+    objects_per_slab: usize,
+    slab_order: usize,
+    constructor: Option<fn(NonNull<u8>)>,
+    inner: Mutex<CacheInner>,
+    registered: Once,
+}
+
+impl SlabCache {
+    /// Creates a cache for objects of `layout`. `constructor` runs on every object when its slab
+    /// is created.
+    pub const fn new(
+        name: &'static str,
+        layout: Layout,
+        constructor: Option<fn(NonNull<u8>)>,
+    ) -> Self {
+        assert!(
+            layout.align() <= FRAME_SIZE,
+            "slab objects can't be aligned to more than a frame"
+        );
+
+        let stride = layout.size().next_multiple_of(layout.align());
+        let stride = if stride == 0 { layout.align() } else { stride };
+        let first_object = size_of::<SlabHeader>().next_multiple_of(layout.align());
+
+        let mut slab_order = 0;
+        let objects_per_slab = loop {
+            let fitting = ((FRAME_SIZE << slab_order) - first_object) / stride;
+            let fitting = if fitting > MAX_OBJECTS {
+                MAX_OBJECTS
+            } else {
+                fitting
+            };
+
+            if fitting >= MIN_OBJECTS || slab_order == MAX_SLAB_ORDER {
+                break fitting;
+            }
+            slab_order += 1;
+        };
+
+        assert!(objects_per_slab > 0, "slab objects too large");
+
+        Self {
+            name,
+            stride,
+            object_size: layout.size(),
+            align: layout.align(),
+            first_object,
+            objects_per_slab,
+            slab_order,
+            constructor,
+            inner: Mutex::new(CacheInner {
+                partial: SlabList::new(),
+                full: SlabList::new(),
+                empty: SlabList::new(),
+                active_objects: 0,
+                allocations: 0,
+                frees: 0,
+            }),
+            registered: Once::new(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Allocates an object in its constructed state, `None` if no frame is left for a new slab
+    pub fn alloc(&'static self) -> Option<NonNull<u8>> {
+        self.alloc_with(|order| {
+            FRAME_ALLOCATOR
+                .get()
+                .expect("frame allocator not initialized")
+                .lock()
+                .allocate_contiguous(order)
+        })
+    }
+
+    /// Like [`Self::alloc`], for callers that already hold the frame allocator
+    pub fn alloc_locked(
+        &'static self,
+        frame_allocator: &mut KernelFrameAllocator,
+    ) -> Option<NonNull<u8>> {
+        self.alloc_with(|order| frame_allocator.allocate_contiguous(order))
+    }
+
+    fn alloc_with(
+        &'static self,
+        allocate_slab: impl FnOnce(usize) -> Option<PhysFrame>,
+    ) -> Option<NonNull<u8>> {
+        self.registered.call_once(|| register(self));
+
+        interrupts::without_interrupts(|| {
+            if let Some(object) = self.take_cached() {
+                return Some(object);
+            }
+
+            // the cache is unlocked while the slab is allocated, so the new slab joins the others
+            // and the object comes from whichever slab is best by then
+            let slab = self.new_slab(allocate_slab(self.slab_order)?);
+            unsafe { self.inner.lock().empty.push(slab) };
+
+            self.take_cached()
+        })
+    }
+
+    /// Takes an object from a slab that is already in the cache
+    fn take_cached(&self) -> Option<NonNull<u8>> {
+        let mut inner = self.inner.lock();
+
+        let slab = if !inner.partial.head.is_null() {
+            let slab = inner.partial.head;
+            unsafe { inner.partial.remove(slab) };
+            slab
+        } else if !inner.empty.head.is_null() {
+            let slab = inner.empty.head;
+            unsafe { inner.empty.remove(slab) };
+            slab
+        } else {
+            return None;
+        };
+
+        let object = unsafe { self.take_object(slab) };
+        unsafe { inner.push_slab(slab, self.objects_per_slab) };
+        inner.active_objects += 1;
+        inner.allocations += 1;
+
+        Some(object)
+    }
+
+    /// # Safety
+    ///
+    /// `object` must have been returned by [`Self::alloc`] of this cache, must be in its
+    /// constructed state and must not be used afterwards
+    pub unsafe fn free(&self, object: NonNull<u8>) {
+        let slab = self.slab_of(object);
+        let index = (object.as_ptr() as usize - slab as usize - self.first_object) / self.stride;
+
+        interrupts::without_interrupts(|| {
+            let mut inner = self.inner.lock();
+
+            unsafe {
+                let was_full = (*slab).in_use == self.objects_per_slab;
+                let free = &mut (*slab).free[index / 64];
+                debug_assert!(
+                    *free & (1 << (index % 64)) == 0,
+                    "double free of a slab object"
+                );
+
+                *free |= 1 << (index % 64);
+                (*slab).in_use -= 1;
+
+                if was_full {
+                    inner.full.remove(slab);
+                } else {
+                    inner.partial.remove(slab);
+                }
+
+                if (*slab).in_use == 0 {
+                    inner.empty.push(slab);
+                } else {
+                    inner.partial.push(slab);
+                }
+            }
+
+            inner.active_objects -= 1;
+            inner.frees += 1;
+        })
+    }
+
+    /// Hands all empty slabs back to the frame allocator, returns the number of bytes released.
+    /// Does nothing if the cache is in use right now.
+    pub fn reap(&self) -> usize {
+        interrupts::without_interrupts(|| {
+            let Some(mut inner) = self.inner.try_lock() else {
+                return 0;
+            };
+            let mut empty = core::mem::replace(&mut inner.empty, SlabList::new());
+            drop(inner);
+
+            let mut released = 0;
+            while !empty.head.is_null() {
+                let slab = empty.head;
+                unsafe {
+                    empty.remove(slab);
+                    self.free_slab(slab);
+                }
+                released += self.slab_size();
+            }
+
+            released
+        })
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        interrupts::without_interrupts(|| {
+            let inner = self.inner.lock();
+
+            SlabStats {
+                name: self.name,
+                object_size: self.object_size,
+                objects_per_slab: self.objects_per_slab,
+                slab_size: self.slab_size(),
+                slabs: inner.partial.len + inner.full.len + inner.empty.len,
+                empty_slabs: inner.empty.len,
+                active_objects: inner.active_objects,
+                allocations: inner.allocations,
+                frees: inner.frees,
+            }
+        })
+    }
+
+    fn slab_size(&self) -> usize {
+        FRAME_SIZE << self.slab_order
+    }
+
+    /// Initializes a slab in `frame` that isn't on any list yet
+    fn new_slab(&self, frame: PhysFrame) -> *mut SlabHeader {
+        let slab: *mut SlabHeader = super::phys_to_virt(frame.start_address()).as_mut_ptr();
+
+        let mut free = [0; MAX_OBJECTS / 64];
+        for index in 0..self.objects_per_slab {
+            free[index / 64] |= 1 << (index % 64);
+        }
+
+        unsafe {
+            slab.write(SlabHeader {
+                next: ptr::null_mut(),
+                prev: ptr::null_mut(),
+                in_use: 0,
+                free,
+            });
+        }
+
+        if let Some(constructor) = self.constructor {
+            for index in 0..self.objects_per_slab {
+                constructor(self.object_at(slab, index));
+            }
+        }
+
+        slab
+    }
+
+    unsafe fn free_slab(&self, slab: *mut SlabHeader) {
+        let phys = PhysAddr::new(slab as u64 - super::hhdm_offset());
+
+        interrupts::without_interrupts(|| unsafe {
+            FRAME_ALLOCATOR
+                .get()
+                .expect("frame allocator not initialized")
+                .lock()
+                .deallocate_contiguous(PhysFrame::containing_address(phys), self.slab_order)
+        })
+    }
+
+    /// Marks the first free object of a slab with at least one free object as used
+    unsafe fn take_object(&self, slab: *mut SlabHeader) -> NonNull<u8> {
+        let header = unsafe { &mut *slab };
+
+        let (word, bits) = header
+            .free
+            .iter_mut()
+            .enumerate()
+            .find(|(_, bits)| **bits != 0)
+            .expect("slab on the partial list has no free object");
+
+        let bit = bits.trailing_zeros() as usize;
+        *bits &= !(1 << bit);
+        header.in_use += 1;
+
+        self.object_at(slab, word * 64 + bit)
+    }
+
+    fn object_at(&self, slab: *mut SlabHeader, index: usize) -> NonNull<u8> {
+        let addr = slab as usize + self.first_object + index * self.stride;
+        NonNull::new(addr as *mut u8).expect("slab object at null")
+    }
+
+    /// Slabs are naturally aligned in physical memory, so the header is found by aligning down
+    fn slab_of(&self, object: NonNull<u8>) -> *mut SlabHeader {
+        let phys = PhysAddr::new(object.as_ptr() as u64 - super::hhdm_offset());
+        let slab = phys.align_down(self.slab_size() as u64);
+        super::phys_to_virt(slab).as_mut_ptr()
+    }
+}
+
+unsafe impl Allocator for &'static SlabCache {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() > self.object_size || layout.align() > self.align {
+            return Err(AllocError);
+        }
+
+        let object = self.alloc().ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(object, self.object_size))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        unsafe { self.free(ptr) }
+    }
+}
+
+fn register(cache: &'static SlabCache) {
+    interrupts::without_interrupts(|| {
+        let mut caches = CACHES.lock();
+        let slot = caches
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .expect("too many slab caches");
+        *slot = Some(cache);
+    })
+}
+
+/// Every cache that has been used so far
+fn caches() -> [Option<&'static SlabCache>; MAX_CACHES] {
+    interrupts::without_interrupts(|| *CACHES.lock())
+}
+
+/// Releases the empty slabs of every cache, returns the number of bytes released
+pub fn shrink_all() -> usize {
+    caches().iter().flatten().map(|cache| cache.reap()).sum()
+}
+
+pub fn slab_stats() -> Vec<SlabStats> {
+    caches()
+        .iter()
+        .flatten()
+        .map(|cache| cache.stats())
+        .collect()
+}
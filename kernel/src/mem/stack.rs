@@ -0,0 +1,98 @@
+//! Kernel stacks with guard pages.
+//!
+//! Every stack is a region of its own, so the guard gap [`super::vma`] leaves below it stays
+//! unmapped and running off the bottom of the stack faults instead of corrupting its neighbour.
+
+use core::arch::asm;
+
+use x86_64::{
+    VirtAddr,
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+};
+
+use super::{
+    vma::{self, Backing, RegionKind, VmaError},
+    vmm::{self, VmmError},
+};
+
+pub const KERNEL_STACK_SIZE: u64 = 64 * 1024; // 64 KiB
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    Vma(VmaError),
+    Vmm(VmmError),
+}
+
+impl From<VmaError> for StackError {
+    fn from(err: VmaError) -> Self {
+        StackError::Vma(err)
+    }
+}
+
+impl From<VmmError> for StackError {
+    fn from(err: VmmError) -> Self {
+        StackError::Vmm(err)
+    }
+}
+
+pub struct KernelStack {
+    bottom: VirtAddr,
+    size: u64,
+}
+
+impl KernelStack {
+    /// Allocates a stack of `size` bytes, rounded up to whole pages. All of it is mapped up front
+    /// since a fault on the stack can't be handled on that same stack.
+    pub fn new(size: u64) -> Result<Self, StackError> {
+        let size = size.next_multiple_of(Size4KiB::SIZE);
+        let bottom = vma::reserve(
+            size,
+            Size4KiB::SIZE,
+            RegionKind::KernelStack,
+            Backing::Mapped,
+        )?;
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        if let Err(err) = vmm::allocate_range(bottom, size, flags) {
+            vma::release(bottom)?;
+            return Err(err.into());
+        }
+
+        Ok(Self { bottom, size })
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    /// Initial stack pointer, the stack grows down from here
+    pub fn top(&self) -> VirtAddr {
+        self.bottom + self.size
+    }
+
+    /// Moves execution onto this stack by calling `entry` on it
+    ///
+    /// # Safety
+    ///
+    /// The current stack is abandoned, nothing on it may be referenced by `entry`
+    pub unsafe fn switch_to(&'static self, entry: extern "C" fn() -> !) -> ! {
+        unsafe {
+            asm!(
+            "mov rsp, {top}",
+            "xor ebp, ebp",
+            "call {entry}",
+            "ud2",
+            top = in(reg) self.top().as_u64(),
+            entry = in(reg) entry,
+            options(noreturn)
+            );
+        }
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        vmm::unmap_range(self.bottom, self.size).expect("kernel stack is not mapped");
+        vma::release(self.bottom).expect("kernel stack is not reserved");
+    }
+}
@@ -0,0 +1,221 @@
+//! Memory usage reporting.
+
+use limine::memory_map::EntryType;
+use spin::Once;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{PageTable, PageTableFlags},
+};
+
+use super::{
+    FRAME_ALLOCATOR, MAPPER,
+    frame_allocator::{self, MAX_NODES},
+    heap::{self, HeapStats},
+    slab,
+    swap::{self, SwapStats},
+};
+use crate::println;
+
+const FRAME_SIZE: u64 = 4096;
+
+const ENTRY_TYPES: [(EntryType, &str); 8] = [
+    (EntryType::USABLE, "Usable"),
+    (EntryType::RESERVED, "Reserved"),
+    (EntryType::ACPI_RECLAIMABLE, "AcpiReclaimable"),
+    (EntryType::ACPI_NVS, "AcpiNvs"),
+    (EntryType::BAD_MEMORY, "BadMemory"),
+    (EntryType::BOOTLOADER_RECLAIMABLE, "BootloaderReclaimable"),
+    (EntryType::EXECUTABLE_AND_MODULES, "ExecutableAndModules"),
+    (EntryType::FRAMEBUFFER, "Framebuffer"),
+];
+
+/// One entry per known entry type, followed by one for types this kernel doesn't know
+const SUMMARY_LEN: usize = ENTRY_TYPES.len() + 1;
+
+// the memory map itself is gone once bootloader memory is reclaimed
+static MEMORY_MAP_SUMMARY: Once<[RegionStats; SUMMARY_LEN]> = Once::new();
+
+/// All memory map entries of one type, as reported at boot
+#[derive(Debug, Clone, Copy)]
+pub struct RegionStats {
+    pub name: &'static str,
+    pub regions: usize,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemStats {
+    /// Bytes covered by the memory map, whatever their type
+    pub total_bytes: u64,
+    /// Frames owned by the frame allocator, grows as boot memory is reclaimed
+    pub usable_frames: u64,
+    pub free_frames: u64,
+    pub numa_nodes: usize,
+    /// Free frames of every numa node, only the first `numa_nodes` entries are used
+    pub node_free_frames: [u64; MAX_NODES],
+    /// Bytes of the frame allocator's per-frame bookkeeping
+    pub frame_metadata_bytes: u64,
+    /// Bytes of the kernel page tables
+    pub page_table_bytes: u64,
+    pub memory_map: [RegionStats; SUMMARY_LEN],
+    pub heap: HeapStats,
+    pub swap: SwapStats,
+}
+
+impl MemStats {
+    pub fn used_frames(&self) -> u64 {
+        self.usable_frames - self.free_frames
+    }
+}
+
+/// Summarizes the memory map while it is still around
+pub(super) fn init() {
+    MEMORY_MAP_SUMMARY.call_once(|| {
+        let mut summary = [RegionStats {
+            name: "Other",
+            regions: 0,
+            bytes: 0,
+        }; SUMMARY_LEN];
+
+        for (stats, (_, name)) in summary.iter_mut().zip(ENTRY_TYPES) {
+            stats.name = name;
+        }
+
+        for entry in frame_allocator::memory_map() {
+            let index = ENTRY_TYPES
+                .iter()
+                .position(|(entry_type, _)| *entry_type == entry.entry_type)
+                .unwrap_or(ENTRY_TYPES.len());
+
+            summary[index].regions += 1;
+            summary[index].bytes += entry.length;
+        }
+
+        summary
+    });
+}
+
+pub fn mem_stats() -> MemStats {
+    let memory_map = *MEMORY_MAP_SUMMARY
+        .get()
+        .expect("memory stats not initialized");
+
+    let (usable_frames, free_frames, metadata_frames, numa_nodes, node_free_frames) =
+        interrupts::without_interrupts(|| {
+            let frame_allocator = FRAME_ALLOCATOR
+                .get()
+                .expect("frame allocator not initialized")
+                .lock();
+
+            (
+                frame_allocator.managed_frames(),
+                frame_allocator.free_frames(),
+                frame_allocator.metadata_frames(),
+                frame_allocator.node_count(),
+                core::array::from_fn(|node| frame_allocator.node_free_frames(node)),
+            )
+        });
+
+    let page_tables = interrupts::without_interrupts(|| {
+        let mapper = MAPPER.get().expect("mapper not initialized").lock();
+        count_page_tables(mapper.level_4_table(), 4)
+    });
+
+    MemStats {
+        total_bytes: memory_map.iter().map(|stats| stats.bytes).sum(),
+        usable_frames,
+        free_frames,
+        numa_nodes,
+        node_free_frames,
+        frame_metadata_bytes: metadata_frames * FRAME_SIZE,
+        page_table_bytes: page_tables * FRAME_SIZE,
+        memory_map,
+        heap: heap::heap_stats(),
+        swap: swap::swap_stats(),
+    }
+}
+
+/// Prints a meminfo style report to the console and serial
+pub fn print_meminfo() {
+    let stats = mem_stats();
+    let heap = stats.heap;
+
+    let rows = [
+        ("MemTotal", stats.total_bytes),
+        ("MemUsable", stats.usable_frames * FRAME_SIZE),
+        ("MemFree", stats.free_frames * FRAME_SIZE),
+        ("MemUsed", stats.used_frames() * FRAME_SIZE),
+        ("FrameMetadata", stats.frame_metadata_bytes),
+        ("PageTables", stats.page_table_bytes),
+        ("HeapSize", heap.size_bytes as u64),
+        ("HeapLimit", heap.limit_bytes as u64),
+        ("HeapAllocated", heap.allocated_bytes as u64),
+        ("HeapFree", heap.free_bytes as u64),
+        ("HeapPeak", heap.peak_allocated_bytes as u64),
+        ("SwapTotal", stats.swap.total_slots * FRAME_SIZE),
+        (
+            "SwapFree",
+            (stats.swap.total_slots - stats.swap.used_slots) * FRAME_SIZE,
+        ),
+    ];
+
+    for (name, bytes) in rows {
+        println!("{:<24}{:>12} KiB", name, bytes / 1024);
+    }
+
+    if stats.numa_nodes > 1 {
+        println!("\nNUMA nodes:");
+        for (node, free) in stats.node_free_frames[..stats.numa_nodes]
+            .iter()
+            .enumerate()
+        {
+            println!("Node{:<20}{:>12} KiB free", node, free * FRAME_SIZE / 1024);
+        }
+    }
+
+    println!("\nMemory map at boot:");
+    for region in stats.memory_map.iter().filter(|stats| stats.regions > 0) {
+        println!(
+            "{:<24}{:>12} KiB in {} regions",
+            region.name,
+            region.bytes / 1024,
+            region.regions
+        );
+    }
+
+    let caches = slab::slab_stats();
+    if !caches.is_empty() {
+        println!("\nSlab caches:");
+    }
+    for cache in caches {
+        println!(
+            "{:<24}{:>8}/{:<8} objects of {} bytes in {} slabs",
+            cache.name,
+            cache.active_objects,
+            cache.slabs * cache.objects_per_slab,
+            cache.object_size,
+            cache.slabs
+        );
+    }
+}
+
+/// Number of tables in the hierarchy below `table`, including itself
+fn count_page_tables(table: &PageTable, level: u8) -> u64 {
+    if level == 1 {
+        return 1;
+    }
+
+    let children: u64 = table
+        .iter()
+        .filter(|entry| {
+            let flags = entry.flags();
+            flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE)
+        })
+        .map(|entry| {
+            let child: &PageTable = unsafe { &*super::phys_to_virt(entry.addr()).as_ptr() };
+            count_page_tables(child, level - 1)
+        })
+        .sum();
+
+    1 + children
+}
@@ -0,0 +1,339 @@
+//! Swapping of anonymous user pages.
+//!
+//! Once [`enable`] has been given a block device, usually a swap partition, running out of frames
+//! no longer fails page faults right away. [`reclaim`] looks for user pages whose accessed bit
+//! stayed clear since the previous scan, writes them to a free slot of the device and replaces
+//! their page table entry with a swap entry: a not-present entry marked [`SWAPPED`] that holds
+//! the slot number where the frame address would be. Touching such a page faults and the fault
+//! handler reads it back into a fresh frame.
+//!
+//! Only pages mapped by a single address space are swapped out. A swapped out page that gets
+//! forked is shared through its slot instead, which is reference counted like frames are.
+//!
+//! The device is never accessed while the frame allocator or the swap area is locked, as drivers
+//! may allocate memory. Pages are swapped out in a [`SwapBatch`]: their entries become swap
+//! entries marked [`IN_FLIGHT`] while the frame allocator is locked, the pages are written after
+//! it has been released and the entries are finished, or restored if the write failed, once it
+//! is locked again. The regions lock of the address space is held throughout, so nothing else
+//! sees the entries in flight.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+
+use spin::Mutex;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::interrupts,
+    structures::paging::{
+        FrameDeallocator, PageSize, PageTableFlags, PhysFrame, Size4KiB, page_table::PageTableEntry,
+    },
+};
+
+use super::{FRAME_ALLOCATOR, address_space, vmm::VmmError};
+use crate::drivers::block::{BlockDevice, SECTOR_SIZE};
+
+/// Software bit marking a not-present entry whose page is in swap
+pub const SWAPPED: PageTableFlags = PageTableFlags::BIT_10;
+/// Software bit marking a swap entry whose page is still being written
+pub const IN_FLIGHT: PageTableFlags = PageTableFlags::BIT_11;
+
+/// Most pages swapped out with one round of I/O
+pub(super) const BATCH_SIZE: usize = 32;
+
+const SECTORS_PER_SLOT: u64 = Size4KiB::SIZE / SECTOR_SIZE as u64;
+
+static SWAP: Mutex<Option<SwapArea>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapError {
+    /// Swap is already enabled on another device
+    AlreadyEnabled,
+    /// The device can't hold a single page
+    DeviceTooSmall,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SwapStats {
+    pub total_slots: u64,
+    pub used_slots: u64,
+}
+
+struct SwapArea {
+    device: Arc<dyn BlockDevice>,
+    /// Number of page table entries referring to every slot, 0 for free slots
+    slots: Vec<u16>,
+    used: usize,
+    /// Where the search for a free slot starts, so that slots are handed out round robin
+    next: usize,
+}
+
+impl SwapArea {
+    fn allocate_slot(&mut self) -> Option<usize> {
+        let len = self.slots.len();
+        let slot = (0..len)
+            .map(|i| (self.next + i) % len)
+            .find(|&slot| self.slots[slot] == 0)?;
+
+        self.slots[slot] = 1;
+        self.used += 1;
+        self.next = (slot + 1) % len;
+
+        Some(slot)
+    }
+
+    fn release_slot(&mut self, slot: usize) {
+        let count = &mut self.slots[slot];
+        assert!(*count > 0, "releasing free swap slot {slot}");
+
+        *count -= 1;
+        if *count == 0 {
+            self.used -= 1;
+        }
+    }
+}
+
+/// Starts swapping to `device`, whose contents are overwritten
+pub fn enable(device: Arc<dyn BlockDevice>) -> Result<(), SwapError> {
+    let slots = (device.sector_count() / SECTORS_PER_SLOT) as usize;
+    if slots == 0 {
+        return Err(SwapError::DeviceTooSmall);
+    }
+    let slots = vec![0; slots];
+
+    interrupts::without_interrupts(|| {
+        let mut swap = SWAP.lock();
+        if swap.is_some() {
+            return Err(SwapError::AlreadyEnabled);
+        }
+
+        *swap = Some(SwapArea {
+            device,
+            slots,
+            used: 0,
+            next: 0,
+        });
+
+        Ok(())
+    })
+}
+
+pub fn swap_stats() -> SwapStats {
+    interrupts::without_interrupts(|| match SWAP.lock().as_ref() {
+        Some(swap) => SwapStats {
+            total_slots: swap.slots.len() as u64,
+            used_slots: swap.used as u64,
+        },
+        None => SwapStats::default(),
+    })
+}
+
+/// Swaps out up to `pages` cold user pages, returns how many frames were freed. Must not be called
+/// while the heap is locked, which rules out kernel page faults.
+pub fn reclaim(pages: usize) -> usize {
+    let enabled = interrupts::without_interrupts(|| SWAP.try_lock().is_some_and(|s| s.is_some()));
+    if !enabled {
+        return 0;
+    }
+
+    address_space::swap_out_cold_pages(pages)
+}
+
+pub(super) fn is_swap_entry(entry: &PageTableEntry) -> bool {
+    let flags = entry.flags();
+    !flags.contains(PageTableFlags::PRESENT) && flags.contains(SWAPPED)
+}
+
+/// A page on its way to swap
+struct Victim {
+    entry: *mut PageTableEntry,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    slot: usize,
+}
+
+/// Pages picked for swapping out, whose entries are in flight until [`Self::write_out`]
+pub(super) struct SwapBatch {
+    victims: [Option<Victim>; BATCH_SIZE],
+    len: usize,
+    limit: usize,
+}
+
+impl SwapBatch {
+    /// An empty batch that takes up to `limit` pages
+    pub(super) fn new(limit: usize) -> Self {
+        Self {
+            victims: [const { None }; BATCH_SIZE],
+            len: 0,
+            limit: limit.min(BATCH_SIZE),
+        }
+    }
+
+    /// Whether the batch takes no more pages, because it is full or swap is
+    pub(super) fn is_full(&self) -> bool {
+        self.len == self.limit
+    }
+
+    /// Takes a slot for the page `entry` maps and turns the entry into a swap entry in flight.
+    /// Returns false if swap is full, in which case nothing changes.
+    ///
+    /// # Safety
+    ///
+    /// `entry` must map a 4 KiB user page nobody else maps and stay valid until the batch has been
+    /// written out, the caller has to flush it from the TLB
+    pub(super) unsafe fn add(&mut self, entry: &mut PageTableEntry) -> bool {
+        let slot = interrupts::without_interrupts(|| {
+            SWAP.lock().as_mut().and_then(|swap| swap.allocate_slot())
+        });
+        let Some(slot) = slot else {
+            self.limit = self.len;
+            return false;
+        };
+
+        self.victims[self.len] = Some(Victim {
+            entry,
+            frame: PhysFrame::containing_address(entry.addr()),
+            flags: entry.flags(),
+            slot,
+        });
+        self.len += 1;
+        entry.set_addr(slot_addr(slot), SWAPPED | IN_FLIGHT);
+
+        true
+    }
+
+    /// Writes every page to its slot, then frees the frames of the pages that were written and
+    /// maps the others again. Returns the number of pages swapped out.
+    ///
+    /// # Safety
+    ///
+    /// Must be called without holding the frame allocator, but with the locks that kept the
+    /// entries valid since they were added
+    pub(super) unsafe fn write_out(mut self) -> usize {
+        if self.len == 0 {
+            return 0;
+        }
+
+        let device = interrupts::without_interrupts(|| {
+            SWAP.lock()
+                .as_ref()
+                .map(|swap| swap.device.clone())
+                .expect("swap slots without swap")
+        });
+
+        let mut written = [false; BATCH_SIZE];
+        for (victim, written) in self.victims[..self.len].iter().flatten().zip(&mut written) {
+            let sector = victim.slot as u64 * SECTORS_PER_SLOT;
+            match device.write(sector, unsafe { frame_bytes(victim.frame) }) {
+                Ok(()) => *written = true,
+                Err(err) => crate::warning!("failed to write swap slot {}: {err:?}", victim.slot),
+            }
+        }
+
+        interrupts::without_interrupts(|| {
+            let mut frame_allocator = FRAME_ALLOCATOR
+                .get()
+                .expect("frame allocator not initialized")
+                .lock();
+            let mut swap = SWAP.lock();
+            let swap = swap.as_mut().expect("swap slots without swap");
+
+            let victims = self.victims[..self.len].iter_mut().map(|v| v.take());
+            for (victim, &written) in victims.flatten().zip(&written) {
+                let entry = unsafe { &mut *victim.entry };
+                if written {
+                    entry.set_addr(slot_addr(victim.slot), SWAPPED);
+                    unsafe { frame_allocator.deallocate_frame(victim.frame) };
+                } else {
+                    swap.release_slot(victim.slot);
+                    entry.set_addr(victim.frame.start_address(), victim.flags);
+                }
+            }
+        });
+
+        written.iter().filter(|&&written| written).count()
+    }
+}
+
+/// Reads the page of a swap entry for `page` into `frame` and maps it with `flags`. Nothing
+/// changes if the read fails, and `frame` stays with the caller.
+///
+/// # Safety
+///
+/// Must be called without holding the frame allocator, but with the lock that keeps `entry`
+/// valid. `frame` must be unused.
+pub(super) unsafe fn read_in(
+    entry: &mut PageTableEntry,
+    frame: PhysFrame,
+    page: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<(), VmmError> {
+    assert!(
+        !entry.flags().contains(IN_FLIGHT),
+        "reading a swap entry that is still being written"
+    );
+
+    let slot = entry_slot(entry);
+    let device = interrupts::without_interrupts(|| {
+        SWAP.lock()
+            .as_ref()
+            .map(|swap| swap.device.clone())
+            .expect("swap entry without swap")
+    });
+
+    if let Err(err) = device.read(slot as u64 * SECTORS_PER_SLOT, unsafe {
+        frame_bytes(frame)
+    }) {
+        crate::warning!("failed to read swap slot {slot}: {err:?}");
+        return Err(VmmError::SwapFailed(page));
+    }
+
+    interrupts::without_interrupts(|| {
+        SWAP.lock()
+            .as_mut()
+            .expect("swap entry without swap")
+            .release_slot(slot)
+    });
+    entry.set_frame(frame, flags);
+
+    Ok(())
+}
+
+/// Takes another reference to the slot of a swap entry, for a copy of the entry
+pub(super) fn share_entry(entry: &PageTableEntry) {
+    interrupts::without_interrupts(|| {
+        let mut swap = SWAP.lock();
+        let swap = swap.as_mut().expect("swap entry without swap");
+        let count = &mut swap.slots[entry_slot(entry)];
+        *count = count.checked_add(1).expect("swap slot shared too often");
+    })
+}
+
+/// Drops the reference of a swap entry to its slot and clears the entry
+pub(super) fn free_entry(entry: &mut PageTableEntry) {
+    interrupts::without_interrupts(|| {
+        let mut swap = SWAP.lock();
+        let swap = swap.as_mut().expect("swap entry without swap");
+        swap.release_slot(entry_slot(entry));
+    });
+
+    entry.set_unused();
+}
+
+fn entry_slot(entry: &PageTableEntry) -> usize {
+    (entry.addr().as_u64() / Size4KiB::SIZE) as usize
+}
+
+fn slot_addr(slot: usize) -> PhysAddr {
+    PhysAddr::new(slot as u64 * Size4KiB::SIZE)
+}
+
+/// # Safety
+///
+/// Nothing else may access the frame while the slice lives
+unsafe fn frame_bytes<'a>(frame: PhysFrame) -> &'a mut [u8] {
+    unsafe {
+        core::slice::from_raw_parts_mut(
+            super::phys_to_virt(frame.start_address()).as_mut_ptr(),
+            Size4KiB::SIZE as usize,
+        )
+    }
+}
@@ -0,0 +1,280 @@
+//! Bookkeeping of which parts of the kernel address space are in use.
+//!
+//! Subsystems reserve virtual ranges here instead of hardcoding addresses. Reservations never
+//! overlap and are separated by unmapped guard gaps, so running off the end of one region faults
+//! instead of corrupting its neighbour. Reserving a range doesn't map anything, regions with
+//! [`Backing::Demand`] get their frames from the page fault handler on first access.
+
+use spin::{Mutex, Once};
+use x86_64::{
+    VirtAddr,
+    instructions::interrupts,
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+};
+
+/// Part of the higher half handed out to reservations, above the hhdm and below the kernel image
+const KERNEL_REGIONS_START: u64 = 0xffff_c000_0000_0000;
+const KERNEL_REGIONS_END: u64 = 0xffff_e000_0000_0000;
+
+/// Unmapped space kept free on both sides of every region
+pub const GUARD_SIZE: u64 = Size4KiB::SIZE;
+
+// the table lives outside the heap since the heap itself is one of the regions
+const MAX_REGIONS: usize = 256;
+
+pub static KERNEL_REGIONS: Once<Mutex<RegionTable>> = Once::new();
+
+pub fn init() {
+    KERNEL_REGIONS.call_once(|| {
+        Mutex::new(RegionTable::new(
+            VirtAddr::new(KERNEL_REGIONS_START),
+            VirtAddr::new(KERNEL_REGIONS_END),
+        ))
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Heap,
+    KernelStack,
+    Mmio,
+    /// General purpose virtually contiguous memory
+    Vmalloc,
+    /// Memory in the lower half of a user address space
+    User,
+}
+
+/// Where the memory behind a region comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// The owner maps the region itself, faults inside it are bugs
+    Mapped,
+    /// Pages are backed by zeroed frames mapped with these flags when first touched
+    Demand(PageTableFlags),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub kind: RegionKind,
+    pub backing: Backing,
+}
+
+impl Region {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        (self.start..self.end()).contains(&addr)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// No gap in the managed range is large enough
+    OutOfAddressSpace,
+    /// The region table is full
+    TooManyRegions,
+    /// No region starts at the given address
+    NotReserved(VirtAddr),
+    /// The requested range overlaps a region or lies outside the managed range
+    Unavailable(VirtAddr),
+}
+
+/// Sorted list of non-overlapping regions within `start..end`
+#[derive(Clone)]
+pub struct RegionTable {
+    start: u64,
+    end: u64,
+    regions: [Option<Region>; MAX_REGIONS],
+    len: usize,
+}
+
+impl RegionTable {
+    pub const fn new(start: VirtAddr, end: VirtAddr) -> Self {
+        Self {
+            start: start.as_u64(),
+            end: end.as_u64(),
+            regions: [None; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// Reserves `size` bytes aligned to `align`, both rounded up to whole pages
+    pub fn reserve(
+        &mut self,
+        size: u64,
+        align: u64,
+        kind: RegionKind,
+        backing: Backing,
+    ) -> Result<VirtAddr, VmaError> {
+        if self.len == MAX_REGIONS {
+            return Err(VmaError::TooManyRegions);
+        }
+
+        let size = size.next_multiple_of(Size4KiB::SIZE);
+        let align = align.max(Size4KiB::SIZE);
+
+        // first fit, looking at the gap in front of every region and the one after the last
+        let mut index = 0;
+        let mut candidate = (self.start + GUARD_SIZE).next_multiple_of(align);
+
+        while index < self.len {
+            let region = self.region(index);
+
+            if fits_before(candidate, size, region.start.as_u64()) {
+                break;
+            }
+
+            candidate = (region.end().as_u64() + GUARD_SIZE).next_multiple_of(align);
+            index += 1;
+        }
+
+        if index == self.len && !fits_before(candidate, size, self.end) {
+            return Err(VmaError::OutOfAddressSpace);
+        }
+
+        let start = VirtAddr::new(candidate);
+        self.regions[index..=self.len].rotate_right(1);
+        self.regions[index] = Some(Region {
+            start,
+            size,
+            kind,
+            backing,
+        });
+        self.len += 1;
+
+        Ok(start)
+    }
+
+    /// Reserves `size` bytes, rounded up to whole pages, at exactly `start`. Unlike
+    /// [`Self::reserve`] this doesn't keep a guard gap to the neighbouring regions.
+    pub fn reserve_at(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        kind: RegionKind,
+        backing: Backing,
+    ) -> Result<VirtAddr, VmaError> {
+        if self.len == MAX_REGIONS {
+            return Err(VmaError::TooManyRegions);
+        }
+
+        let size = size.next_multiple_of(Size4KiB::SIZE);
+        let end = start
+            .as_u64()
+            .checked_add(size)
+            .ok_or(VmaError::Unavailable(start))?;
+
+        if !start.is_aligned(Size4KiB::SIZE) || start.as_u64() < self.start || end > self.end {
+            return Err(VmaError::Unavailable(start));
+        }
+
+        let index = (0..self.len)
+            .find(|&i| self.region(i).start >= start)
+            .unwrap_or(self.len);
+
+        let overlaps_previous = index > 0 && self.region(index - 1).end() > start;
+        let overlaps_next = index < self.len && self.region(index).start.as_u64() < end;
+        if overlaps_previous || overlaps_next {
+            return Err(VmaError::Unavailable(start));
+        }
+
+        self.regions[index..=self.len].rotate_right(1);
+        self.regions[index] = Some(Region {
+            start,
+            size,
+            kind,
+            backing,
+        });
+        self.len += 1;
+
+        Ok(start)
+    }
+
+    /// Removes the region starting at `start`. The caller must unmap it first.
+    pub fn release(&mut self, start: VirtAddr) -> Result<Region, VmaError> {
+        let index = (0..self.len)
+            .find(|&i| self.region(i).start == start)
+            .ok_or(VmaError::NotReserved(start))?;
+
+        let region = self.regions[index].take().expect("region table has a hole");
+        self.regions[index..self.len].rotate_left(1);
+        self.len -= 1;
+
+        Ok(region)
+    }
+
+    /// Region containing `addr`, if any
+    pub fn find(&self, addr: VirtAddr) -> Option<Region> {
+        self.regions[..self.len]
+            .iter()
+            .flatten()
+            .find(|region| region.contains(addr))
+            .copied()
+    }
+
+    /// Region whose lower guard gap contains `addr`, which is where an overflowing stack ends up
+    pub fn find_by_guard(&self, addr: VirtAddr) -> Option<Region> {
+        self.iter()
+            .find(|region| (region.start - GUARD_SIZE..region.start).contains(&addr))
+            .copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions[..self.len].iter().flatten()
+    }
+
+    fn region(&self, index: usize) -> Region {
+        self.regions[index].expect("region table has a hole")
+    }
+}
+
+/// Whether a region of `size` bytes at `start` leaves a guard gap in front of `limit`
+fn fits_before(start: u64, size: u64, limit: u64) -> bool {
+    start
+        .checked_add(size + GUARD_SIZE)
+        .is_some_and(|end| end <= limit)
+}
+
+/// Reserves a range in the kernel address space
+pub fn reserve(
+    size: u64,
+    align: u64,
+    kind: RegionKind,
+    backing: Backing,
+) -> Result<VirtAddr, VmaError> {
+    with_kernel_regions(|regions| regions.reserve(size, align, kind, backing))
+}
+
+/// Releases a range previously returned by [`reserve`]. The caller must unmap it first.
+pub fn release(start: VirtAddr) -> Result<Region, VmaError> {
+    with_kernel_regions(|regions| regions.release(start))
+}
+
+/// Kernel region containing `addr`, if any
+pub fn find(addr: VirtAddr) -> Option<Region> {
+    with_kernel_regions(|regions| regions.find(addr))
+}
+
+/// Kernel region whose lower guard gap contains `addr`, if any
+pub fn find_by_guard(addr: VirtAddr) -> Option<Region> {
+    with_kernel_regions(|regions| regions.find_by_guard(addr))
+}
+
+/// Like [`find_by_guard`], but gives up if the regions are locked, for exception handlers that
+/// may have interrupted their owner
+pub fn try_find_by_guard(addr: VirtAddr) -> Option<Region> {
+    KERNEL_REGIONS.get()?.try_lock()?.find_by_guard(addr)
+}
+
+fn with_kernel_regions<R>(f: impl FnOnce(&mut RegionTable) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        f(&mut KERNEL_REGIONS
+            .get()
+            .expect("kernel regions not initialized")
+            .lock())
+    })
+}
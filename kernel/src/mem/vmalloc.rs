@@ -0,0 +1,59 @@
+//! Virtually contiguous anonymous kernel memory.
+//!
+//! Allocations only reserve address space, frames are added by the page fault handler as the pages
+//! get touched.
+
+use x86_64::{
+    VirtAddr,
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+};
+
+use super::{
+    vma::{self, Backing, RegionKind, VmaError},
+    vmm::{self, VmmError},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmallocError {
+    Vma(VmaError),
+    Vmm(VmmError),
+}
+
+impl From<VmaError> for VmallocError {
+    fn from(err: VmaError) -> Self {
+        VmallocError::Vma(err)
+    }
+}
+
+impl From<VmmError> for VmallocError {
+    fn from(err: VmmError) -> Self {
+        VmallocError::Vmm(err)
+    }
+}
+
+/// Reserves `size` bytes of zero-initialized memory
+pub fn vmalloc(size: u64) -> Result<VirtAddr, VmallocError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    Ok(vma::reserve(
+        size,
+        Size4KiB::SIZE,
+        RegionKind::Vmalloc,
+        Backing::Demand(flags),
+    )?)
+}
+
+/// Frees memory returned by [`vmalloc`]
+///
+/// # Safety
+///
+/// Nothing may access the memory afterwards
+pub unsafe fn vfree(addr: VirtAddr) -> Result<(), VmallocError> {
+    let region = vma::find(addr)
+        .filter(|r| r.start == addr && r.kind == RegionKind::Vmalloc)
+        .ok_or(VmaError::NotReserved(addr))?;
+
+    vmm::unmap_committed_range(region.start, region.size)?;
+    vma::release(region.start)?;
+
+    Ok(())
+}
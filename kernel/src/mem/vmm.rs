@@ -0,0 +1,417 @@
+//! Fallible mapping primitives on top of the kernel page tables.
+//!
+//! All functions disable interrupts while they hold the page table locks, so they can be called
+//! from interrupt handlers as long as the interrupted code doesn't hold [`MAPPER`] or
+//! [`FRAME_ALLOCATOR`] itself. The locks are always taken in that order.
+
+use core::arch::x86_64::__cpuid;
+
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::interrupts,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+        PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+        mapper::{FlagUpdateError, MapToError, TranslateResult, UnmapError},
+    },
+};
+
+use super::{FRAME_ALLOCATOR, MAPPER, frame_allocator::KernelFrameAllocator};
+
+/// PAT bit of a 4 KiB page table entry. Huge page entries keep their PAT bit in bit 12, which the
+/// x86_64 crate takes for part of the frame address, so modes using the PAT bit are only ever
+/// mapped with 4 KiB pages.
+pub const PAT_4KIB: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+/// Memory type of a mapping, selected through the PAT, PCD and PWT bits with the layout
+/// programmed by [`crate::arch::pat::init`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    /// Uncached, but can be overridden to write-combining by the MTRRs
+    UncachedMinus,
+    Uncached,
+    /// Uncached, but writes are buffered and combined into bursts. Meant for framebuffers.
+    WriteCombining,
+}
+
+impl CacheMode {
+    /// Flags selecting this mode, with the PAT bit given as [`PAT_4KIB`]
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::UncachedMinus => PageTableFlags::NO_CACHE,
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining => PAT_4KIB | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmmError {
+    /// No frame was left for the mapping or one of its page tables
+    OutOfMemory,
+    /// The page is already mapped to a different frame
+    AlreadyMapped(VirtAddr),
+    /// The page is not mapped
+    NotMapped(VirtAddr),
+    /// The range covers only part of a huge page
+    PartialHugePage(VirtAddr),
+    /// The page table entry points to a frame that is not a valid physical address
+    InvalidFrame(VirtAddr),
+    /// The page is in swap but couldn't be read back
+    SwapFailed(VirtAddr),
+    /// The flags select the PAT bit for a huge page
+    HugePageCacheMode(VirtAddr),
+}
+
+/// Maps `len` bytes of physical memory at `virt`, using the largest page size that the alignment
+/// of both addresses and the remaining length allow. Cache modes using the PAT bit always get
+/// 4 KiB pages.
+///
+/// Pages that already map the same physical memory are left untouched. On error the pages mapped
+/// before the failing one stay mapped.
+pub fn map_range(
+    phys: PhysAddr,
+    virt: VirtAddr,
+    len: u64,
+    flags: PageTableFlags,
+) -> Result<(), VmmError> {
+    // both addresses share the same offset into their page
+    let page_offset = phys.as_u64() % Size4KiB::SIZE;
+    let (phys, virt, len) = (phys - page_offset, virt - page_offset, len + page_offset);
+
+    let huge_pages = !flags.contains(PAT_4KIB);
+
+    with_page_tables(|mapper, frame_allocator| {
+        let mut offset = 0;
+        while offset < len {
+            let (phys, virt, remaining) = (phys + offset, virt + offset, len - offset);
+
+            offset += if huge_pages
+                && supports_1gib_pages()
+                && fits::<Size1GiB>(phys, virt, remaining)
+                && map_page::<Size1GiB>(mapper, frame_allocator, phys, virt, flags)?
+            {
+                Size1GiB::SIZE
+            } else if huge_pages
+                && fits::<Size2MiB>(phys, virt, remaining)
+                && map_page::<Size2MiB>(mapper, frame_allocator, phys, virt, flags)?
+            {
+                Size2MiB::SIZE
+            } else {
+                map_page::<Size4KiB>(mapper, frame_allocator, phys, virt, flags)?;
+                Size4KiB::SIZE
+            };
+        }
+
+        Ok(())
+    })
+}
+
+/// Backs `len` bytes at `virt` with freshly allocated, zeroed frames. Nothing stays mapped on
+/// error.
+pub fn allocate_range(virt: VirtAddr, len: u64, flags: PageTableFlags) -> Result<(), VmmError> {
+    with_page_tables(|mapper, frame_allocator| {
+        for page in pages(virt, len) {
+            if let Err(err) = allocate_page(mapper, frame_allocator, page, flags) {
+                let mapped = page.start_address() - virt.align_down(Size4KiB::SIZE);
+                unmap_locked(mapper, frame_allocator, virt, mapped, true, false)?;
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Unmaps `len` bytes at `virt` and returns the frames behind them to the frame allocator
+pub fn unmap_range(virt: VirtAddr, len: u64) -> Result<(), VmmError> {
+    with_page_tables(|mapper, frame_allocator| {
+        unmap_locked(mapper, frame_allocator, virt, len, true, false)
+    })
+}
+
+/// Like [`unmap_range`], but skips pages that aren't mapped, for demand paged regions where only
+/// the touched pages have frames
+pub fn unmap_committed_range(virt: VirtAddr, len: u64) -> Result<(), VmmError> {
+    with_page_tables(|mapper, frame_allocator| {
+        unmap_locked(mapper, frame_allocator, virt, len, true, true)
+    })
+}
+
+/// Unmaps `len` bytes at `virt` without touching the frames behind them, for memory the frame
+/// allocator doesn't own such as MMIO
+pub fn unmap_mmio_range(virt: VirtAddr, len: u64) -> Result<(), VmmError> {
+    with_page_tables(|mapper, frame_allocator| {
+        unmap_locked(mapper, frame_allocator, virt, len, false, false)
+    })
+}
+
+/// Like [`unmap_mmio_range`], but skips pages that aren't mapped, to undo a [`map_range`] that
+/// failed halfway
+pub fn unmap_partial_mmio_range(virt: VirtAddr, len: u64) -> Result<(), VmmError> {
+    with_page_tables(|mapper, frame_allocator| {
+        unmap_locked(mapper, frame_allocator, virt, len, false, true)
+    })
+}
+
+/// Replaces the flags of every page mapping `len` bytes at `virt`. Fails on huge pages if the
+/// flags contain [`PAT_4KIB`].
+pub fn protect_range(virt: VirtAddr, len: u64, flags: PageTableFlags) -> Result<(), VmmError> {
+    with_page_tables(|mapper, _| {
+        walk_mapped(mapper, virt, len, false, |mapper, addr, size| match size {
+            Size4KiB::SIZE => protect_page::<Size4KiB>(mapper, addr, flags),
+            Size2MiB::SIZE => protect_page::<Size2MiB>(mapper, addr, flags),
+            _ => protect_page::<Size1GiB>(mapper, addr, flags),
+        })
+    })
+}
+
+fn with_page_tables<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut KernelFrameAllocator) -> R,
+) -> R {
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.get().expect("mapper not initialized").lock();
+        let mut frame_allocator = FRAME_ALLOCATOR
+            .get()
+            .expect("frame allocator not initialized")
+            .lock();
+
+        f(&mut mapper, &mut frame_allocator)
+    })
+}
+
+/// Maps a single page, returns false if the page can't be mapped with this size because parts of
+/// it are already mapped with smaller pages
+fn map_page<S: PageSize>(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut KernelFrameAllocator,
+    phys: PhysAddr,
+    virt: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<bool, VmmError>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    let page = Page::<S>::containing_address(virt);
+    let frame = PhysFrame::<S>::containing_address(phys);
+
+    match unsafe { map_to(mapper, page, frame, flags, frame_allocator) } {
+        Ok(()) => return Ok(true),
+        Err(MapToError::FrameAllocationFailed) => return Err(VmmError::OutOfMemory),
+        Err(MapToError::PageAlreadyMapped(_) | MapToError::ParentEntryHugePage) => {}
+    }
+
+    // either the page itself or a huge page containing it is already mapped, which is fine as
+    // long as it maps the same physical memory
+    match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: mapped,
+            offset,
+            ..
+        } if mapped.size() >= S::SIZE => {
+            if mapped.start_address() + offset == frame.start_address() {
+                Ok(true)
+            } else {
+                Err(VmmError::AlreadyMapped(page.start_address()))
+            }
+        }
+        TranslateResult::InvalidFrameAddress(_) => {
+            Err(VmmError::InvalidFrame(page.start_address()))
+        }
+        _ if S::SIZE == Size4KiB::SIZE => Err(VmmError::AlreadyMapped(page.start_address())),
+        _ => Ok(false),
+    }
+}
+
+fn allocate_page(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut KernelFrameAllocator,
+    page: Page,
+    flags: PageTableFlags,
+) -> Result<(), VmmError> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(VmmError::OutOfMemory)?;
+
+    unsafe {
+        super::phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, Size4KiB::SIZE as usize);
+    }
+
+    match unsafe { map_to(mapper, page, frame, flags, frame_allocator) } {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+
+            Err(match err {
+                MapToError::FrameAllocationFailed => VmmError::OutOfMemory,
+                _ => VmmError::AlreadyMapped(page.start_address()),
+            })
+        }
+    }
+}
+
+/// [`Mapper::map_to`] that understands [`PAT_4KIB`] on 4 KiB pages, which it would otherwise
+/// reject as the huge page bit
+pub(super) unsafe fn map_to<S: PageSize>(
+    mapper: &mut OffsetPageTable,
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+    frame_allocator: &mut KernelFrameAllocator,
+) -> Result<(), MapToError<S>>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    assert!(
+        S::SIZE == Size4KiB::SIZE || !flags.contains(PAT_4KIB),
+        "huge pages can't use the PAT bit"
+    );
+
+    if !flags.contains(PAT_4KIB) {
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator) }?.flush();
+        return Ok(());
+    }
+
+    // the entry is written not present first, so that the page is never reachable through a
+    // mapping of another memory type
+    let parent_flags = flags
+        & (PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE);
+    let entry_flags = flags - PAT_4KIB - PageTableFlags::PRESENT;
+    unsafe {
+        mapper.map_to_with_table_flags(page, frame, entry_flags, parent_flags, frame_allocator)
+    }?
+    .ignore();
+    unsafe { mapper.update_flags(page, flags) }
+        .expect("page was just mapped")
+        .flush();
+
+    Ok(())
+}
+
+pub(super) fn unmap_locked(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut KernelFrameAllocator,
+    virt: VirtAddr,
+    len: u64,
+    free_frames: bool,
+    skip_holes: bool,
+) -> Result<(), VmmError> {
+    walk_mapped(
+        mapper,
+        virt,
+        len,
+        skip_holes,
+        |mapper, addr, size| match size {
+            Size4KiB::SIZE => unmap_page::<Size4KiB>(mapper, frame_allocator, addr, free_frames),
+            Size2MiB::SIZE => unmap_page::<Size2MiB>(mapper, frame_allocator, addr, free_frames),
+            _ => unmap_page::<Size1GiB>(mapper, frame_allocator, addr, free_frames),
+        },
+    )
+}
+
+fn unmap_page<S: PageSize>(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut KernelFrameAllocator,
+    addr: VirtAddr,
+    free_frame: bool,
+) -> Result<(), VmmError>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+    KernelFrameAllocator: FrameDeallocator<S>,
+{
+    let (frame, flush) =
+        mapper
+            .unmap(Page::<S>::containing_address(addr))
+            .map_err(|err| match err {
+                UnmapError::InvalidFrameAddress(_) => VmmError::InvalidFrame(addr),
+                UnmapError::PageNotMapped | UnmapError::ParentEntryHugePage => {
+                    VmmError::NotMapped(addr)
+                }
+            })?;
+    flush.flush();
+
+    if free_frame {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+
+    Ok(())
+}
+
+fn protect_page<S: PageSize>(
+    mapper: &mut OffsetPageTable,
+    addr: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<(), VmmError>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    if S::SIZE != Size4KiB::SIZE && flags.contains(PAT_4KIB) {
+        return Err(VmmError::HugePageCacheMode(addr));
+    }
+
+    let page = Page::<S>::containing_address(addr);
+    let flush = unsafe { mapper.update_flags(page, flags) }.map_err(|err| match err {
+        FlagUpdateError::PageNotMapped | FlagUpdateError::ParentEntryHugePage => {
+            VmmError::NotMapped(addr)
+        }
+    })?;
+    flush.flush();
+
+    Ok(())
+}
+
+/// Calls `f` with the address and size of every page in the range, failing if a huge page sticks
+/// out of the range or, unless `skip_holes` is set, a page isn't mapped
+fn walk_mapped(
+    mapper: &mut OffsetPageTable,
+    virt: VirtAddr,
+    len: u64,
+    skip_holes: bool,
+    mut f: impl FnMut(&mut OffsetPageTable, VirtAddr, u64) -> Result<(), VmmError>,
+) -> Result<(), VmmError> {
+    let end = (virt + len).align_up(Size4KiB::SIZE);
+    let mut addr = virt.align_down(Size4KiB::SIZE);
+
+    while addr < end {
+        let size = match mapper.translate(addr) {
+            TranslateResult::Mapped { frame, .. } => frame.size(),
+            TranslateResult::NotMapped if skip_holes => {
+                addr += Size4KiB::SIZE;
+                continue;
+            }
+            TranslateResult::NotMapped => return Err(VmmError::NotMapped(addr)),
+            TranslateResult::InvalidFrameAddress(_) => return Err(VmmError::InvalidFrame(addr)),
+        };
+
+        if !addr.is_aligned(size) || end - addr < size {
+            return Err(VmmError::PartialHugePage(addr));
+        }
+
+        f(mapper, addr, size)?;
+        addr += size;
+    }
+
+    Ok(())
+}
+
+fn pages(virt: VirtAddr, len: u64) -> impl Iterator<Item = Page> {
+    let start = Page::containing_address(virt);
+    let end = Page::containing_address(virt + len - 1);
+    Page::range_inclusive(start, end)
+}
+
+fn fits<S: PageSize>(phys: PhysAddr, virt: VirtAddr, len: u64) -> bool {
+    phys.is_aligned(S::SIZE) && virt.is_aligned(S::SIZE) && len >= S::SIZE
+}
+
+fn supports_1gib_pages() -> bool {
+    #[allow(unused_unsafe)]
+    let edx = unsafe { __cpuid(0x8000_0001) }.edx;
+    edx & (1 << 26) != 0
+}
@@ -1,41 +1,114 @@
-use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
+use alloc::{alloc::Global, boxed::Box, collections::BTreeMap, sync::Arc};
 use core::{
-    sync::atomic::{AtomicU8, Ordering},
-    task::{Context, Poll, Waker},
+    alloc::{AllocError, Allocator, Layout},
+    pin::Pin,
+    ptr::NonNull,
+    sync::atomic::{AtomicU8, AtomicU16, AtomicUsize, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
 };
 
 use crossbeam_queue::ArrayQueue;
-use futures_util::{future::BoxFuture, task, task::ArcWake};
 use spin::{Mutex, Once};
 
+use crate::mem::slab::SlabCache;
+
 pub static ASYNC_EXECUTOR: Once<Mutex<TaskExecutor>> = Once::new();
 
 const MAX_TASKS: usize = 128;
 
+// u16 so that every task id plus NO_TASK fits
+static CURRENT_TASK: AtomicU16 = AtomicU16::new(NO_TASK);
+const NO_TASK: u16 = u16::MAX;
+
+/// Futures up to this size live in the task cache, larger ones on the heap
+const TASK_SLOT_SIZE: usize = 256;
+
+static TASK_CACHE: SlabCache = SlabCache::new("task", Layout::new::<TaskSlot>(), None);
+static WAKER_CACHE: SlabCache = SlabCache::new("task_waker", Layout::new::<TaskWaker>(), None);
+
+static WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(clone_waker, wake, wake_by_ref, drop_waker);
+
 pub fn init() {
     ASYNC_EXECUTOR.call_once(|| Mutex::new(TaskExecutor::new()));
 }
 
+/// Id of the task being polled right now, if any
+pub fn current_task() -> Option<u8> {
+    match CURRENT_TASK.load(Ordering::Relaxed) {
+        NO_TASK => None,
+        id => Some(id as u8),
+    }
+}
+
 pub struct Task {
-    future: BoxFuture<'static, ()>,
+    future: Pin<Box<dyn Future<Output = ()> + Send, TaskAllocator>>,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Self {
         Self {
-            future: Box::pin(future),
+            future: Box::pin_in(future, TaskAllocator),
         }
     }
 }
 
+/// Only gives the task cache its layout
+#[repr(align(16))]
+struct TaskSlot {
+    _bytes: [u8; TASK_SLOT_SIZE],
+}
+
+/// Puts futures that fit into the task cache and the others on the heap
+#[derive(Debug, Clone, Copy)]
+struct TaskAllocator;
+
+impl TaskAllocator {
+    fn fits(layout: Layout) -> bool {
+        layout.size() <= size_of::<TaskSlot>() && layout.align() <= align_of::<TaskSlot>()
+    }
+}
+
+unsafe impl Allocator for TaskAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if Self::fits(layout) {
+            (&TASK_CACHE).allocate(layout)
+        } else {
+            Global.allocate(layout)
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        unsafe {
+            if Self::fits(layout) {
+                (&TASK_CACHE).deallocate(ptr, layout)
+            } else {
+                Global.deallocate(ptr, layout)
+            }
+        }
+    }
+}
+
+/// Reference counted by hand, as wakers in the waker cache can't go through `Arc`
 struct TaskWaker {
+    refs: AtomicUsize,
     id: u8,
     task_queue: Arc<ArrayQueue<u8>>,
 }
 
 impl TaskWaker {
     fn new_waker(id: u8, task_queue: Arc<ArrayQueue<u8>>) -> Waker {
-        task::waker(Arc::new(Self { id, task_queue }))
+        let waker = Box::new_in(
+            Self {
+                refs: AtomicUsize::new(1),
+                id,
+                task_queue,
+            },
+            &WAKER_CACHE,
+        );
+        let (waker, _) = Box::into_raw_with_allocator(waker);
+
+        unsafe { Waker::from_raw(RawWaker::new(waker.cast(), &WAKER_VTABLE)) }
     }
 
     fn wake_task(&self) {
@@ -43,13 +116,28 @@ impl TaskWaker {
     }
 }
 
-impl ArcWake for TaskWaker {
-    fn wake(self: Arc<Self>) {
-        self.wake_task()
+unsafe fn clone_waker(waker: *const ()) -> RawWaker {
+    let task_waker = unsafe { &*waker.cast::<TaskWaker>() };
+    task_waker.refs.fetch_add(1, Ordering::Relaxed);
+    RawWaker::new(waker, &WAKER_VTABLE)
+}
+
+unsafe fn wake(waker: *const ()) {
+    unsafe {
+        wake_by_ref(waker);
+        drop_waker(waker);
     }
+}
 
-    fn wake_by_ref(arc_self: &Arc<Self>) {
-        arc_self.wake_task()
+unsafe fn wake_by_ref(waker: *const ()) {
+    unsafe { &*waker.cast::<TaskWaker>() }.wake_task()
+}
+
+unsafe fn drop_waker(waker: *const ()) {
+    let task_waker = waker.cast::<TaskWaker>().cast_mut();
+    if unsafe { &*task_waker }.refs.fetch_sub(1, Ordering::Release) == 1 {
+        core::sync::atomic::fence(Ordering::Acquire);
+        drop(unsafe { Box::from_raw_in(task_waker, &WAKER_CACHE) });
     }
 }
 
@@ -94,7 +182,11 @@ impl TaskExecutor {
                 .or_insert_with(|| TaskWaker::new_waker(task_id, self.task_queue.clone()));
             let mut context = Context::from_waker(waker);
 
-            match task.future.as_mut().poll(&mut context) {
+            CURRENT_TASK.store(task_id as u16, Ordering::Relaxed);
+            let poll = task.future.as_mut().poll(&mut context);
+            CURRENT_TASK.store(NO_TASK, Ordering::Relaxed);
+
+            match poll {
                 Poll::Ready(_) => {
                     self.tasks.remove(&task_id);
                     self.waker_cache.remove(&task_id);
@@ -0,0 +1,93 @@
+//! The monotonic clock.
+//!
+//! The architecture offers its counters as [`ClockSource`]s and [`init`] keeps the one with the
+//! highest rating. Every reading is converted to nanoseconds since then.
+
+use alloc::vec::Vec;
+use core::{
+    hint,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use spin::Once;
+
+use super::Instant;
+use crate::println;
+
+static CLOCK: Once<Clock> = Once::new();
+/// Latest reading handed out, so that time never goes backwards
+static LAST_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// A counter running at a fixed frequency
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// How good the source is, the best available source has the highest rating
+    fn rating(&self) -> u32;
+
+    /// Counts per second
+    fn frequency(&self) -> u64;
+
+    /// The current count
+    fn read(&self) -> u64;
+
+    /// Called once before the source is used as the clock
+    fn enable(&self) {}
+}
+
+struct Clock {
+    source: &'static dyn ClockSource,
+    frequency: u64,
+    /// Count at the time the clock was initialized
+    start: u64,
+}
+
+/// Picks the best of `sources` as the clock
+pub fn init(sources: Vec<&'static dyn ClockSource>) {
+    let source = sources
+        .into_iter()
+        .max_by_key(|source| source.rating())
+        .expect("no clock source available");
+
+    source.enable();
+    let frequency = source.frequency();
+    assert!(
+        frequency > 0,
+        "clock source {} has no frequency",
+        source.name()
+    );
+
+    println!("clock source: {} at {} Hz", source.name(), frequency);
+
+    CLOCK.call_once(|| Clock {
+        source,
+        frequency,
+        start: source.read(),
+    });
+}
+
+/// Name of the source the clock reads
+pub fn current_source() -> Option<&'static str> {
+    CLOCK.get().map(|clock| clock.source.name())
+}
+
+/// The current point in time on the monotonic clock
+pub fn monotonic_now() -> Instant {
+    let clock = CLOCK.get().expect("clock not initialized");
+
+    let count = clock.source.read().saturating_sub(clock.start);
+    let nanos = (count as u128 * 1_000_000_000 / clock.frequency as u128) as u64;
+
+    // some sources briefly jump back, like the lapic timer around a reload
+    let last = LAST_NANOS.fetch_max(nanos, Ordering::Relaxed);
+    Instant::from_nanos(nanos.max(last))
+}
+
+/// Busy waits for `micros` microseconds
+pub fn udelay(micros: u64) {
+    let end = monotonic_now() + Duration::from_micros(micros);
+    while monotonic_now() < end {
+        hint::spin_loop();
+    }
+}
@@ -0,0 +1,28 @@
+//! Time keeping.
+//!
+//! [`monotonic_now`] reads the best clock source of the machine, [`realtime_now`] adds the wall
+//! clock time it was started at and the [`tick`] drives periodic work, like the [`timer`] futures.
+//! They are set up by [`init`] and [`crate::arch::init`].
+
+pub mod clocksource;
+pub mod instant;
+pub mod realtime;
+pub mod tick;
+pub mod timer;
+mod wheel;
+
+pub use core::time::Duration;
+
+pub use clocksource::{monotonic_now, udelay};
+pub use instant::Instant;
+pub use realtime::realtime_now;
+pub use timer::{interval, sleep, sleep_until, timeout};
+
+/// Starts the monotonic clock and sets the wall clock, must be called after
+/// [`crate::arch::init`]
+pub fn init() {
+    clocksource::init(crate::arch::clocksource::clock_sources());
+    realtime::init(crate::arch::rtc::read());
+
+    tick::register_callback(wheel::on_tick).expect("failed to register the timer wheel");
+}
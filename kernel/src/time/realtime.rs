@@ -0,0 +1,160 @@
+//! Wall clock time.
+//!
+//! The calendar time is read once at boot, from the rtc or from the bootloader when the rtc is
+//! unusable, and advanced with the monotonic clock from then on.
+
+use core::{fmt, time::Duration};
+
+use limine::request::DateAtBootRequest;
+use spin::Once;
+
+use super::{Instant, monotonic_now};
+use crate::println;
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static DATE_AT_BOOT_REQUEST: DateAtBootRequest = DateAtBootRequest::new();
+
+/// Unix time at the instant the wall clock was set
+static BOOT_TIME: Once<(Duration, Instant)> = Once::new();
+
+const SECS_PER_DAY: u64 = 86400;
+
+/// A UTC calendar date and time of day
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    pub fn is_valid(&self) -> bool {
+        (1970..=9999).contains(&self.year)
+            && (1..=12).contains(&self.month)
+            && (1..=days_in_month(self.year, self.month)).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Seconds since the unix epoch
+    pub fn to_unix(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days as u64 * SECS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    /// The date and time `secs` seconds after the unix epoch
+    pub fn from_unix(secs: u64) -> Self {
+        let (year, month, day) = civil_from_days((secs / SECS_PER_DAY) as i64);
+        let secs_of_day = secs % SECS_PER_DAY;
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Sets the wall clock from `rtc`, or from the bootloader if the rtc's time isn't valid. Must be
+/// called after the monotonic clock has been initialized and before bootloader memory is
+/// reclaimed.
+pub fn init(rtc: DateTime) {
+    let unix_time = if rtc.is_valid() {
+        Some(Duration::from_secs(rtc.to_unix()))
+    } else {
+        crate::warning!("rtc reports an invalid time: {rtc:?}");
+        DATE_AT_BOOT_REQUEST
+            .get_response()
+            .map(|response| response.timestamp())
+    };
+
+    let Some(unix_time) = unix_time else {
+        crate::warning!("no wall clock time available");
+        return;
+    };
+
+    let now = monotonic_now();
+    BOOT_TIME.call_once(|| (unix_time, now));
+
+    println!("wall clock: {}", DateTime::from_unix(unix_time.as_secs()));
+}
+
+/// Time since the unix epoch, `None` if no source of wall clock time was found
+pub fn realtime_now() -> Option<Duration> {
+    let (unix_time, set_at) = BOOT_TIME.get()?;
+    Some(*unix_time + monotonic_now().duration_since(*set_at))
+}
+
+/// The current date and time, `None` if no source of wall clock time was found
+pub fn now() -> Option<DateTime> {
+    realtime_now().map(|time| DateTime::from_unix(time.as_secs()))
+}
+
+fn is_leap_year(year: u16) -> bool {
+    year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// the two conversions below count in eras of 400 years from march 1st, see
+// http://howardhinnant.github.io/date_algorithms.html
+
+/// Days since 1970-01-01 of a proleptic gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// Proleptic gregorian date of a number of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
@@ -0,0 +1,69 @@
+//! The periodic system tick.
+//!
+//! The architecture's timer interrupt calls [`handle_tick`] [`frequency`] times per second, which
+//! counts the tick and runs the registered callbacks. Callbacks run in interrupt context with
+//! interrupts disabled, so they must be short and must not block.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Tick frequency set up at boot, in Hz
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+// callbacks are kept outside of the heap so that registering one never allocates
+const MAX_CALLBACKS: usize = 16;
+
+/// Called with the number of ticks since boot
+pub type TickCallback = fn(u64);
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+static CALLBACKS: Mutex<[Option<TickCallback>; MAX_CALLBACKS]> = Mutex::new([None; MAX_CALLBACKS]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickError {
+    /// All callback slots are taken
+    TooManyCallbacks,
+}
+
+/// Runs `callback` on every tick from now on
+pub fn register_callback(callback: TickCallback) -> Result<(), TickError> {
+    interrupts::without_interrupts(|| {
+        let mut callbacks = CALLBACKS.lock();
+        let slot = callbacks
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(TickError::TooManyCallbacks)?;
+        *slot = Some(callback);
+
+        Ok(())
+    })
+}
+
+/// Ticks since the timer was started
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Ticks per second, 0 while the timer isn't running
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Records the frequency the timer was programmed with, for the architecture code
+pub(crate) fn set_frequency(frequency: u32) {
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+}
+
+/// Counts a tick and runs the callbacks, called from the timer interrupt
+pub(crate) fn handle_tick() {
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    // copied out so that callbacks may register further callbacks
+    let callbacks = *CALLBACKS.lock();
+    for callback in callbacks.iter().flatten() {
+        callback(ticks);
+    }
+}
@@ -0,0 +1,198 @@
+//! Futures that wait for time to pass.
+//!
+//! They are woken by the [`wheel`] at the first tick after their deadline and check the
+//! monotonic clock before completing, so they never finish early, but may finish up to a tick
+//! late.
+
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::Stream;
+
+use super::{
+    Instant, tick,
+    wheel::{self, TimerId},
+};
+
+/// Waits until `duration` has passed
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Waits until `deadline`
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        timer: None,
+    }
+}
+
+/// Runs `future` for at most `duration`
+pub fn timeout<F: Future>(future: F, duration: Duration) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+/// Yields every `period`, starting after the first one. Ticks missed because the task was busy
+/// are skipped rather than delivered in a burst.
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "interval period must not be zero");
+
+    let start = Instant::now() + period;
+    Interval {
+        period,
+        sleep: sleep_until(start),
+    }
+}
+
+/// The future returned by [`sleep`] and [`sleep_until`]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Sleep {
+    deadline: Instant,
+    timer: Option<TimerId>,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Moves the deadline, the future can be polled again afterwards even if it completed
+    pub fn reset(&mut self, deadline: Instant) {
+        self.cancel();
+        self.deadline = deadline;
+    }
+
+    fn cancel(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            wheel::remove(timer);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            self.cancel();
+            return Poll::Ready(());
+        }
+
+        match self.timer {
+            Some(timer) if !wheel::poll(timer, cx.waker()) => {}
+            _ => {
+                // not armed yet, or fired before the deadline because of tick drift
+                self.cancel();
+                let deadline = deadline_tick(now, self.deadline);
+                self.timer = Some(wheel::insert(deadline, cx.waker().clone()));
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// The future returned by [`timeout`]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+/// The error of a [`Timeout`] whose future didn't complete in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl<F> Timeout<F> {
+    pub fn into_inner(self) -> F {
+        self.future
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // the future is never moved out of the pinned timeout, the sleep is Unpin
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+
+        Pin::new(&mut this.sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+/// The stream returned by [`interval`]
+#[derive(Debug)]
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+impl Interval {
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Waits for the next tick and returns when it was due
+    pub async fn tick(&mut self) -> Instant {
+        core::future::poll_fn(|cx| self.poll_tick(cx)).await
+    }
+
+    pub fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<Instant> {
+        if Pin::new(&mut self.sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let due = self.sleep.deadline();
+        let mut next = due + self.period;
+        let now = Instant::now();
+        if next <= now {
+            let missed = (now - next).as_nanos() / self.period.as_nanos() + 1;
+            next += Duration::from_nanos((missed * self.period.as_nanos()) as u64);
+        }
+        self.sleep.reset(next);
+
+        Poll::Ready(due)
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Instant>> {
+        self.get_mut().poll_tick(cx).map(Some)
+    }
+}
+
+/// First tick at or after which `deadline` has passed
+fn deadline_tick(now: Instant, deadline: Instant) -> u64 {
+    let frequency = tick::frequency();
+    assert!(frequency != 0, "timers need the system tick");
+
+    let remaining = deadline.duration_since(now).as_nanos();
+    let ticks = (remaining * frequency as u128).div_ceil(1_000_000_000);
+
+    // the current tick is already partly over
+    tick::ticks()
+        .saturating_add(u64::try_from(ticks).unwrap_or(u64::MAX))
+        .saturating_add(1)
+}
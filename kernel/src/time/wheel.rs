@@ -0,0 +1,219 @@
+//! Hierarchical timer wheel.
+//!
+//! Timers are kept in [`LEVELS`] wheels of [`SLOTS`] slots each. A slot of level 0 holds the
+//! timers expiring at one tick, a slot of level `n` the timers of `SLOTS.pow(n)` consecutive ticks.
+//! Every tick empties the current slot of level 0, and whenever a level wraps around the current
+//! slot of the next level is spread over the levels below it. Adding and firing a timer is
+//! constant time however far out it expires.
+//!
+//! The tick advances the wheel from interrupt context, so that side never allocates or frees:
+//! timers live in a slab linked by index and only change owners there. A fired timer belongs to
+//! its future until the future takes it back, a cancelled one belongs to the wheel until its slot
+//! comes up.
+
+use alloc::vec::Vec;
+use core::task::Waker;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+const LEVELS: usize = 4;
+
+/// Timers further out than this fire early and have to be rearmed
+const MAX_DELAY: u64 = (1 << (SLOT_BITS * LEVELS as u32)) - 1;
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new());
+
+/// Index of a timer in the slab
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Linked into a slot, waiting for its tick
+    Pending,
+    /// Unlinked and woken, waiting for its future to take it back
+    Fired,
+    /// Dropped by its future, waiting for its slot to come up
+    Cancelled,
+    /// On the free list
+    Free,
+}
+
+struct Timer {
+    deadline: u64,
+    waker: Option<Waker>,
+    state: State,
+    next: Option<u32>,
+}
+
+struct Wheel {
+    /// The last tick that was processed
+    now: u64,
+    slots: [[Option<u32>; SLOTS]; LEVELS],
+    timers: Vec<Timer>,
+    free: Option<u32>,
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        Self {
+            now: 0,
+            slots: [[None; SLOTS]; LEVELS],
+            timers: Vec::new(),
+            free: None,
+        }
+    }
+
+    fn allocate(&mut self, deadline: u64, waker: Waker) -> u32 {
+        let timer = Timer {
+            deadline,
+            waker: Some(waker),
+            state: State::Pending,
+            next: None,
+        };
+
+        match self.free {
+            Some(index) => {
+                self.free = self.timers[index as usize].next;
+                self.timers[index as usize] = timer;
+                index
+            }
+            None => {
+                self.timers.push(timer);
+                (self.timers.len() - 1) as u32
+            }
+        }
+    }
+
+    /// Puts a timer on the free list, the caller takes care of its waker
+    fn release(&mut self, index: u32) {
+        let timer = &mut self.timers[index as usize];
+        timer.state = State::Free;
+        timer.next = self.free;
+        self.free = Some(index);
+    }
+
+    fn link(&mut self, index: u32) {
+        let deadline = self.timers[index as usize].deadline;
+        // a delay of 0 only happens while cascading, right before the current slot is emptied
+        let delay = deadline.saturating_sub(self.now).min(MAX_DELAY);
+        let deadline = self.now + delay;
+
+        let level = (0..LEVELS)
+            .find(|&level| delay < 1 << (SLOT_BITS * (level as u32 + 1)))
+            .unwrap_or(LEVELS - 1);
+        let slot = ((deadline >> (SLOT_BITS * level as u32)) & SLOT_MASK) as usize;
+
+        self.timers[index as usize].next = self.slots[level][slot];
+        self.slots[level][slot] = Some(index);
+    }
+
+    /// Processes every tick up to `tick`
+    fn advance(&mut self, tick: u64) {
+        while self.now < tick {
+            self.now += 1;
+
+            if self.now & SLOT_MASK == 0 {
+                self.cascade();
+            }
+
+            let slot = (self.now & SLOT_MASK) as usize;
+            let mut next = self.slots[0][slot].take();
+            while let Some(index) = next {
+                let timer = &mut self.timers[index as usize];
+                next = timer.next.take();
+
+                match timer.state {
+                    State::Pending => {
+                        timer.state = State::Fired;
+                        // the future takes the waker back, dropping it here could free memory
+                        if let Some(waker) = &timer.waker {
+                            waker.wake_by_ref();
+                        }
+                    }
+                    State::Cancelled => self.release(index),
+                    State::Fired | State::Free => unreachable!("timer {index} linked twice"),
+                }
+            }
+        }
+    }
+
+    /// Spreads the current slots of the upper levels over the levels below
+    fn cascade(&mut self) {
+        for level in 1..LEVELS {
+            let slot = ((self.now >> (SLOT_BITS * level as u32)) & SLOT_MASK) as usize;
+
+            let mut next = self.slots[level][slot].take();
+            while let Some(index) = next {
+                next = self.timers[index as usize].next.take();
+                self.link(index);
+            }
+
+            if slot != 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Arms a timer that wakes `waker` once tick `deadline` has been processed
+pub fn insert(deadline: u64, waker: Waker) -> TimerId {
+    interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        // the current slot has already been emptied
+        let deadline = deadline.max(wheel.now + 1);
+        let index = wheel.allocate(deadline, waker);
+        wheel.link(index);
+
+        TimerId(index)
+    })
+}
+
+/// Whether the timer fired. Pending timers get their waker replaced by `waker` if it would wake
+/// another task.
+pub fn poll(id: TimerId, waker: &Waker) -> bool {
+    let old_waker = interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        let timer = &mut wheel.timers[id.0 as usize];
+
+        match timer.state {
+            State::Fired => None,
+            State::Pending => match &timer.waker {
+                Some(old) if old.will_wake(waker) => Some(None),
+                _ => Some(timer.waker.replace(waker.clone())),
+            },
+            State::Cancelled | State::Free => unreachable!("polling released timer {}", id.0),
+        }
+    });
+
+    // a replaced waker gets dropped here, outside of the lock
+    old_waker.is_none()
+}
+
+/// Gives the timer back to the wheel, whether it fired or not
+pub fn remove(id: TimerId) {
+    let waker = interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        let timer = &mut wheel.timers[id.0 as usize];
+        let waker = timer.waker.take();
+
+        match timer.state {
+            State::Fired => wheel.release(id.0),
+            State::Pending => timer.state = State::Cancelled,
+            State::Cancelled | State::Free => unreachable!("removing released timer {}", id.0),
+        }
+
+        waker
+    });
+
+    drop(waker);
+}
+
+/// Advances the wheel to the current tick, registered as a tick callback
+pub(super) fn on_tick(ticks: u64) {
+    WHEEL.lock().advance(ticks);
+}